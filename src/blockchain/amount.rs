@@ -0,0 +1,143 @@
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Number of decimal places in one coin.
+pub const DECIMALS: u32 = 8;
+
+/// Base units in one coin.
+pub const COIN: u64 = 10u64.pow(DECIMALS);
+
+#[derive(Debug, Error, PartialEq)]
+pub enum AmountError {
+    #[error("invalid amount {0:?}")]
+    Invalid(String),
+    #[error("amount {0:?} has more than {DECIMALS} decimal places")]
+    TooPrecise(String),
+    #[error("amount {0:?} is too large")]
+    Overflow(String),
+}
+
+/// A quantity of coins in indivisible base units (1 coin = `COIN` units).
+///
+/// Serialized as the integer number of base units; `Display` and `FromStr`
+/// use the decimal coin notation, e.g. `12.5`.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Amount(u64);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+    pub const MAX: Amount = Amount(u64::MAX);
+
+    pub const fn from_base_units(units: u64) -> Amount {
+        Amount(units)
+    }
+
+    pub const fn from_coins(coins: u64) -> Amount {
+        Amount(coins * COIN)
+    }
+
+    pub const fn base_units(self) -> u64 {
+        self.0
+    }
+
+    pub fn is_zero(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        self.0.checked_add(other.0).map(Amount)
+    }
+
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+        self.0.checked_sub(other.0).map(Amount)
+    }
+
+    /// `percent`% of the amount, rounded down to a whole base unit.
+    pub fn percent(self, percent: u64) -> Amount {
+        Amount((self.0 as u128 * percent as u128 / 100) as u64)
+    }
+
+    /// Sums amounts, returning `None` on overflow.
+    pub fn checked_sum<I: IntoIterator<Item = Amount>>(amounts: I) -> Option<Amount> {
+        amounts.into_iter().try_fold(Amount::ZERO, Amount::checked_add)
+    }
+}
+
+impl Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let whole = self.0 / COIN;
+        let fraction = self.0 % COIN;
+        if fraction == 0 {
+            return write!(f, "{}", whole);
+        }
+        let fraction = format!("{:0width$}", fraction, width = DECIMALS as usize);
+        write!(f, "{}.{}", whole, fraction.trim_end_matches('0'))
+    }
+}
+
+impl FromStr for Amount {
+    type Err = AmountError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || AmountError::Invalid(s.to_string());
+        let (whole, fraction) = s.split_once('.').unwrap_or((s, ""));
+        if whole.is_empty() || !whole.bytes().all(|b| b.is_ascii_digit()) || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        if s.contains('.') && fraction.is_empty() {
+            return Err(invalid());
+        }
+        if fraction.len() > DECIMALS as usize {
+            return Err(AmountError::TooPrecise(s.to_string()));
+        }
+
+        let overflow = || AmountError::Overflow(s.to_string());
+        let whole: u64 = whole.parse().map_err(|_| overflow())?;
+        let fraction: u64 = if fraction.is_empty() {
+            0
+        } else {
+            fraction.parse::<u64>().unwrap() * 10u64.pow(DECIMALS - fraction.len() as u32)
+        };
+        whole
+            .checked_mul(COIN)
+            .and_then(|units| units.checked_add(fraction))
+            .map(Amount)
+            .ok_or_else(overflow)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_and_parses_decimals() {
+        for (text, units) in [("0", 0), ("1", COIN), ("12.5", 1_250_000_000), ("0.00000001", 1), ("0.01", 1_000_000)] {
+            let amount: Amount = text.parse().unwrap();
+            assert_eq!(amount.base_units(), units);
+            assert_eq!(amount.to_string(), text);
+        }
+        assert_eq!("1.50".parse::<Amount>().unwrap().to_string(), "1.5");
+    }
+
+    #[test]
+    fn rejects_malformed_amounts() {
+        for text in ["", ".5", "1.", "-1", "1e3", "1.2.3", " 1"] {
+            assert_eq!(text.parse::<Amount>(), Err(AmountError::Invalid(text.to_string())), "{:?}", text);
+        }
+        assert!(matches!("0.000000001".parse::<Amount>(), Err(AmountError::TooPrecise(_))));
+        assert!(matches!("184467440738".parse::<Amount>(), Err(AmountError::Overflow(_))));
+    }
+
+    #[test]
+    fn arithmetic_is_checked() {
+        assert_eq!(Amount::MAX.checked_add(Amount::from_base_units(1)), None);
+        assert_eq!(Amount::ZERO.checked_sub(Amount::from_base_units(1)), None);
+        assert_eq!(Amount::checked_sum([Amount::MAX, Amount::from_base_units(1)]), None);
+        assert_eq!(Amount::from_coins(3).percent(1), Amount::from_base_units(3_000_000));
+    }
+}
@@ -1,30 +1,92 @@
+use std::fmt::{self, Debug, Display};
+use std::str::FromStr;
 use std::time::{SystemTime, UNIX_EPOCH};
 use rand::Rng;
 use serde::{Serialize, Deserialize};
-use std::fmt::Debug;
+use serde_with::{hex::Hex, serde_as};
 
-use super::transaction::Transaction;
+use super::merkle::{self, MerkleHash, MerkleProof};
+use super::transaction::{Transaction, TransactionID};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Block {
     pub index: u32,
     pub timestamp: u64,
     pub data: String,
-    pub prev_hash: String,
-    pub hash: String,
+    pub prev_hash: BlockID,
+    pub hash: BlockID,
+    #[serde_as(as = "Hex")]
+    pub merkle_root: MerkleHash,
     pub nonce: u64,
     pub transactions: Vec<Transaction>,
     pub difficulty: u32,
 }
 
+/// SHA-256 of the block header
 pub const BLOCK_ID_LENGTH: usize = 32;
 
+/// A block hash. Displayed, parsed and serialized as lowercase hex; the
+/// all-zero ID is the genesis block's `prev_hash`.
 #[derive(Clone, Copy, Default, Eq, Hash, PartialEq)]
 pub struct BlockID([u8; BLOCK_ID_LENGTH]);
 
+impl BlockID {
+    pub fn as_hex(&self) -> String {
+        format!("{}", self)
+    }
+}
+
+impl From<[u8; BLOCK_ID_LENGTH]> for BlockID {
+    fn from(bytes: [u8; BLOCK_ID_LENGTH]) -> Self {
+        BlockID(bytes)
+    }
+}
+
+impl AsRef<[u8]> for BlockID {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Display for BlockID {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl Debug for BlockID {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "BlockID({})", self)
+    }
+}
+
+impl FromStr for BlockID {
+    type Err = hex::FromHexError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut bytes = [0u8; BLOCK_ID_LENGTH];
+        hex::decode_to_slice(s, &mut bytes)?;
+        Ok(BlockID(bytes))
+    }
+}
+
+impl Serialize for BlockID {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for BlockID {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
 #[allow(dead_code)]
 impl Block {
-    pub fn new(index: u32, data: String, prev_hash: String) -> Block {
+    pub fn new(index: u32, data: String, prev_hash: BlockID) -> Block {
         let mut rng = rand::thread_rng();
         let start_nonce = rng.gen_range(0..u64::MAX);
         let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
@@ -33,10 +95,31 @@ impl Block {
             timestamp,
             data,
             prev_hash,
-            hash: String::new(),
+            hash: BlockID::default(),
+            merkle_root: merkle::merkle_root(&[]),
             nonce: start_nonce,
             transactions: vec![],
             difficulty: 0,
         }
     }
+
+    /// Replaces the block's transactions and recomputes `data` and `merkle_root`.
+    pub fn set_transactions(&mut self, transactions: Vec<Transaction>) {
+        self.data = transactions.iter().map(|tx| tx.to_string()).collect::<Vec<_>>().join("\n");
+        self.transactions = transactions;
+        self.merkle_root = self.compute_merkle_root();
+    }
+
+    pub fn transaction_ids(&self) -> Vec<TransactionID> {
+        self.transactions.iter().map(Transaction::id).collect()
+    }
+
+    pub fn compute_merkle_root(&self) -> MerkleHash {
+        merkle::merkle_root(&self.transaction_ids())
+    }
+
+    /// Inclusion proof for `tx_id` against this block's `merkle_root`.
+    pub fn merkle_proof(&self, tx_id: &TransactionID) -> Option<MerkleProof> {
+        MerkleProof::build(&self.transaction_ids(), tx_id)
+    }
 }
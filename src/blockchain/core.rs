@@ -1,155 +1,1123 @@
-use crate::blockchain::block::Block;
-use crate::blockchain::hashing::Hashing;
-use crate::blockchain::transaction::Transaction;
+use crate::blockchain::amount::Amount;
+use crate::blockchain::block::{Block, BlockID};
+use crate::blockchain::hashing::{BlockHeader, Hashing};
+use crate::blockchain::merkle::{self, MerkleHash, MerkleProof};
+use crate::blockchain::transaction::{Transaction, TransactionError, TransactionID};
 use crate::blockchain::transaction_pool::TransactionPool;
+use crate::blockchain::tree::BlockTree;
+use crate::blockchain::wallet::Address;
 use crate::utils::calculations;
-use log::{debug, info};
+use log::{debug, info, warn};
 use serde::Serialize;
-use std::{borrow::BorrowMut, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use thiserror::Error;
 
-use super::db::mongodb::core::MongoDB;
+use super::db::core::{Store, StoreError};
+
+/// Timestamp of the genesis block. It's fixed so that every fresh node
+/// creates the same genesis block and can join the same network.
+pub const GENESIS_TIMESTAMP: u64 = 1_724_000_000;
+
+/// How many blocks `Blockchain::reindex` processes between progress logs.
+const REINDEX_LOG_INTERVAL: usize = 1000;
+
+/// Describes the first block that failed validation, or the store failing
+/// to take a valid one.
+#[derive(Debug, Error)]
+pub enum ChainError {
+    #[error("chain has no blocks")]
+    Empty,
+    #[error("block {index}: invalid genesis block")]
+    InvalidGenesis { index: u32 },
+    #[error("block {index}: expected index {expected}")]
+    IndexMismatch { index: u32, expected: u32 },
+    #[error("block {index}: prev_hash {found} does not match previous block hash {expected}")]
+    PrevHashMismatch { index: u32, expected: BlockID, found: BlockID },
+    #[error("block {index}: hash {found} does not match recomputed hash {expected}")]
+    HashMismatch { index: u32, expected: BlockID, found: BlockID },
+    #[error("block {index}: merkle_root {} does not match transactions ({})", hex::encode(found), hex::encode(expected))]
+    MerkleRootMismatch { index: u32, expected: MerkleHash, found: MerkleHash },
+    #[error("block {index}: difficulty {found} differs from the expected {expected}")]
+    DifficultyMismatch { index: u32, expected: u32, found: u32 },
+    #[error("block {index}: hash does not satisfy difficulty {difficulty}")]
+    InsufficientWork { index: u32, difficulty: u32 },
+    #[error("block {index}: timestamp {timestamp} is before previous block timestamp {prev_timestamp}")]
+    TimestampRegression { index: u32, timestamp: u64, prev_timestamp: u64 },
+    #[error("block {index}: transaction {position} is invalid: {source}")]
+    InvalidTransaction { index: u32, position: usize, source: TransactionError },
+    #[error("block {index}: transaction {position} repeats {id}")]
+    DuplicateTransaction { index: u32, position: usize, id: TransactionID },
+    #[error("block {index}: {source}")]
+    Ledger { index: u32, source: LedgerError },
+    #[error("block {index}: already known")]
+    Duplicate { index: u32 },
+    #[error("block {index}: parent {prev_hash} is unknown")]
+    UnknownParent { index: u32, prev_hash: BlockID },
+    #[error("store error: {0}")]
+    Store(#[from] StoreError),
+}
+
+impl ChainError {
+    /// Whether the block is invalid on its own: its proof of work, merkle
+    /// root or transaction signatures are wrong. Other errors depend on the
+    /// chain it's checked against, which a peer may see differently.
+    pub fn is_invalid_data(&self) -> bool {
+        matches!(
+            self,
+            ChainError::InvalidGenesis { .. }
+                | ChainError::HashMismatch { .. }
+                | ChainError::InsufficientWork { .. }
+                | ChainError::MerkleRootMismatch { .. }
+                | ChainError::InvalidTransaction { .. }
+                | ChainError::DuplicateTransaction { .. }
+        )
+    }
+}
+
+/// Why a transaction or block can't be applied to the current balances.
+#[derive(Debug, Error, PartialEq)]
+pub enum LedgerError {
+    #[error("{address} has {balance} but needs {required}")]
+    InsufficientBalance { address: Address, balance: Amount, required: Amount },
+    #[error("block rewards total {found}, more than the allowed {allowed}")]
+    ExcessiveReward { allowed: Amount, found: Amount },
+    #[error("reward transaction is for height {found:?}, expected {expected}")]
+    RewardHeightMismatch { expected: u32, found: Option<u32> },
+    #[error("balance of {0} overflows")]
+    Overflow(Address),
+    #[error("transaction {0} is already on the chain")]
+    AlreadyConfirmed(TransactionID),
+    #[error("transaction {0} appears more than once in the block")]
+    DuplicateInBlock(TransactionID),
+}
+
+#[derive(Debug, Error)]
+pub enum ReindexError {
+    #[error("stored chain is invalid: {0}")]
+    Chain(#[from] ChainError),
+    #[error("store error: {0}")]
+    Store(#[from] StoreError),
+}
+
+/// What `Blockchain::reindex` rebuilt.
+#[derive(Debug, Clone, Serialize)]
+pub struct ReindexReport {
+    pub blocks: usize,
+    pub transactions: usize,
+    pub wallets: usize,
+}
+
+/// Balances derived by applying every block's transactions in chain order,
+/// and the IDs of those transactions so none is applied twice. This is the
+/// source of truth; the `WALLETS` collection only caches it.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct LedgerState {
+    balances: HashMap<Address, Amount>,
+    #[serde(skip)]
+    confirmed: HashSet<TransactionID>,
+}
+
+impl LedgerState {
+    /// Replays `chain` from genesis.
+    pub fn from_chain(chain: &[Block]) -> Result<LedgerState, ChainError> {
+        let mut ledger = LedgerState::default();
+        for block in chain {
+            ledger
+                .apply_block(block)
+                .map_err(|source| ChainError::Ledger { index: block.index, source })?;
+        }
+        Ok(ledger)
+    }
+
+    pub fn balance(&self, address: &Address) -> Amount {
+        self.balances.get(address).copied().unwrap_or_default()
+    }
+
+    pub fn balances(&self) -> &HashMap<Address, Amount> {
+        &self.balances
+    }
+
+    /// Whether a transaction with this ID is in one of the applied blocks.
+    pub fn is_confirmed(&self, id: &TransactionID) -> bool {
+        self.confirmed.contains(id)
+    }
+
+    /// Addresses whose balance differs in `other`, with their balance there.
+    pub fn changed_balances(&self, other: &LedgerState) -> Vec<(Address, Amount)> {
+        let mut addresses: Vec<&Address> = self.balances.keys().chain(other.balances.keys()).collect();
+        addresses.sort();
+        addresses.dedup();
+        addresses
+            .into_iter()
+            .filter(|address| self.balance(address) != other.balance(address))
+            .map(|address| (*address, other.balance(address)))
+            .collect()
+    }
+
+    /// Applies a single non-reward transaction: the sender pays `amount` plus
+    /// `fee`, the receiver gets `amount`. Leaves the state untouched on error.
+    /// The transaction isn't recorded as confirmed; only blocks do that.
+    pub fn apply_transaction(&mut self, transaction: &Transaction) -> Result<(), LedgerError> {
+        let id = transaction.id();
+        if self.is_confirmed(&id) {
+            return Err(LedgerError::AlreadyConfirmed(id));
+        }
+        let mut changes = HashMap::new();
+        self.stage_transaction(&mut changes, transaction)?;
+        self.balances.extend(changes);
+        Ok(())
+    }
+
+    /// Applies all of `block`'s transactions, checking sender balances, that
+    /// no transaction is already on the chain or listed twice, and that
+    /// rewards don't exceed the subsidy plus scaled fees. Either the whole
+    /// block is applied or nothing is. Returns the new balances of the
+    /// touched addresses.
+    pub fn apply_block(&mut self, block: &Block) -> Result<Vec<(Address, Amount)>, LedgerError> {
+        let mut changes = HashMap::new();
+        let mut ids = HashSet::new();
+        let mut fees = Amount::ZERO;
+        let mut rewards = Amount::ZERO;
+
+        for transaction in &block.transactions {
+            let id = transaction.id();
+            if self.is_confirmed(&id) {
+                return Err(LedgerError::AlreadyConfirmed(id));
+            }
+            if !ids.insert(id) {
+                return Err(LedgerError::DuplicateInBlock(id));
+            }
+            if transaction.is_reward() {
+                if transaction.reward_height != Some(block.index) {
+                    return Err(LedgerError::RewardHeightMismatch { expected: block.index, found: transaction.reward_height });
+                }
+                rewards = rewards.checked_add(transaction.amount).ok_or(LedgerError::Overflow(transaction.receiver))?;
+                self.credit(&mut changes, &transaction.receiver, transaction.amount)?;
+            } else {
+                fees = fees.checked_add(transaction.fee).ok_or(LedgerError::Overflow(transaction.sender))?;
+                self.stage_transaction(&mut changes, transaction)?;
+            }
+        }
+
+        let allowed = calculations::calculate_mining_reward(block.index as u64, fees);
+        if rewards > allowed {
+            return Err(LedgerError::ExcessiveReward { allowed, found: rewards });
+        }
+
+        let touched = changes.iter().map(|(address, balance)| (*address, *balance)).collect();
+        self.balances.extend(changes);
+        self.confirmed.extend(ids);
+        Ok(touched)
+    }
+
+    fn staged_balance(&self, changes: &HashMap<Address, Amount>, address: &Address) -> Amount {
+        changes.get(address).copied().unwrap_or_else(|| self.balance(address))
+    }
+
+    fn credit(&self, changes: &mut HashMap<Address, Amount>, address: &Address, amount: Amount) -> Result<(), LedgerError> {
+        let balance = self.staged_balance(changes, address);
+        let balance = balance.checked_add(amount).ok_or(LedgerError::Overflow(*address))?;
+        changes.insert(*address, balance);
+        Ok(())
+    }
+
+    fn stage_transaction(&self, changes: &mut HashMap<Address, Amount>, transaction: &Transaction) -> Result<(), LedgerError> {
+        let sender = transaction.sender;
+        let required = transaction.amount.checked_add(transaction.fee).ok_or(LedgerError::Overflow(sender))?;
+        let balance = self.staged_balance(changes, &sender);
+        let remaining = balance
+            .checked_sub(required)
+            .ok_or(LedgerError::InsufficientBalance { address: sender, balance, required })?;
+        changes.insert(sender, remaining);
+        self.credit(changes, &transaction.receiver, transaction.amount)
+    }
+}
+
+pub type SharedBlockchain = Arc<Mutex<Blockchain>>;
+
+/// How the main chain changed when a block was added.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChainUpdate {
+    /// Blocks taken off the main chain by a reorganization, oldest first.
+    pub disconnected: Vec<Block>,
+    /// Blocks added to the main chain, oldest first. Empty when the block
+    /// only extended a side branch.
+    pub connected: Vec<Block>,
+}
 
 #[derive(Debug, Clone, Serialize)]
 pub struct Blockchain {
     pub chain: Vec<Block>,
     pub difficulty: u32,
-    pub db: MongoDB,
+    pub ledger: LedgerState,
+    #[serde(skip)]
+    pub tree: BlockTree,
+    #[serde(skip)]
+    pub db: Store,
 }
 
 impl Blockchain {
-    pub async fn new(db: MongoDB) -> Blockchain {
-        let chain = db.get_blocks().await.unwrap_or_default();
+    pub async fn new(db: Store) -> Result<Blockchain, ChainError> {
         let mut blockchain = Blockchain {
-            chain: chain.clone(),
-            difficulty: calculations::calculate_difficulty(&chain),
+            chain: vec![],
+            difficulty: calculations::calculate_difficulty::<Block>(&[]),
+            ledger: LedgerState::default(),
+            tree: BlockTree::default(),
             db,
         };
 
-        blockchain.load_blocks().await;
-        blockchain
-    }
+        blockchain.load_blocks().await?;
+        blockchain.ledger = blockchain.validate_chain()?;
+        blockchain.tree = BlockTree::from_chain(&blockchain.chain);
+        blockchain.difficulty = calculations::calculate_difficulty(&blockchain.chain);
 
-    pub async fn mine_block(&mut self, transaction_pool: &mut TransactionPool, miner_address: &str) -> (Duration, u32) {
-        let start = Instant::now();
-        let prev_block = self.chain.last().unwrap();
-        let mut prev_block_mutable = prev_block.clone();
-        let prev_block_mutable = prev_block_mutable.borrow_mut();
-        let mut transactions = transaction_pool.pool.clone();
-    
-        let chain_len = self.chain.len() as u64;
-        let amount = calculations::calculate_mining_reward(chain_len, &transaction_pool);
-    
-        let reward_transaction = Transaction {
-            sender: "block_reward".to_string(),
-            receiver: miner_address.to_string(),
-            amount,
-            fee: calculations::calculate_fee(amount),
-            timestamp: chrono::Utc::now().timestamp() as u64,
-        };
-        println!("Reward transaction: {:?}", reward_transaction);
-    
-        transactions.push(reward_transaction.clone());
-    
-        prev_block_mutable.transactions.extend(transactions.clone());
-        prev_block_mutable.data = prev_block.transactions.iter().map(|tx| tx.to_string()).collect::<Vec<_>>().join("\n");
-    
-        info!("Mining block...");
-    
-        let mut hasher = Hashing::new(prev_block.clone());
-        hasher.mine_block(self.difficulty);
-        prev_block_mutable.hash = hasher.block.hash.clone();
-        self.create_transaction(reward_transaction.clone()).await;
-        let reward_amount = reward_transaction.clone().amount;
-        let amount = self.db.get_balance(miner_address).await.unwrap_or(0.0);
-        self.db.update_balance(miner_address, amount + reward_amount).await.expect("Failed to update balance");
-        self.db.insert_transaction(&reward_transaction).await.expect("Failed to insert transaction into database");
-        debug!("Reward transaction: {:?}", reward_transaction);
-        transaction_pool.clear_pool();
-        info!("Block mined and transactions added to the chain");
-    
-        if self.chain.len() % 10 == 0 { 
-            self.adjust_difficulty();
+        let stale = blockchain.audit_wallet_cache().await;
+        if !stale.is_empty() {
+            warn!("{} cached wallet balances differ from the chain, rebuilding the cache", stale.len());
+            blockchain.rebuild_wallet_cache().await?;
         }
-    
-        let duration = start.elapsed();
-        (duration, self.difficulty)
+        Ok(blockchain)
     }
 
-    pub async fn create_transaction(&mut self, transaction: Transaction) {
-        if self.chain.is_empty() {
-            self.create_genesis_block().await;
-        } else {
-            let block = self.chain.last_mut().unwrap();
-            block.transactions.push(transaction.clone());
-            block.data = block.transactions.iter().map(|tx| tx.to_string()).collect::<Vec<_>>().join("\n");
-            self.db.update_block_transactions(block, &transaction).await.expect("Failed to update block transactions");
-        }
+    /// The unsolved next block on the tip: the pool transactions that still
+    /// apply, in pool order, followed by a reward paying `miner_address` the
+    /// subsidy and their fees.
+    pub fn block_template(&self, transaction_pool: &TransactionPool, miner_address: &Address) -> Block {
+        self.block_template_paying(transaction_pool, &[(*miner_address, 1)])
     }
 
-    pub fn adjust_difficulty(&mut self) {
-        let last_block = self.chain.last().unwrap();
-        let prev_block = self.chain.get(self.chain.len() - 10).unwrap();
-        let time_diff = last_block.timestamp - prev_block.timestamp;
+    /// Like `block_template`, but the reward is split between `payouts` in
+    /// proportion to their weights, with one reward transaction each. The
+    /// last payout gets what rounding leaves over. Payouts that would get
+    /// nothing are left out, so at least one weight must be positive.
+    pub fn block_template_paying(&self, transaction_pool: &TransactionPool, payouts: &[(Address, u64)]) -> Block {
+        let prev_block = self.chain.last().unwrap();
+        let height = prev_block.index + 1;
 
-        if time_diff < 60 {
-            self.difficulty += 1;
-        } else {
-            self.difficulty -= 1;
+        // Only include pool transactions that still apply on top of the tip.
+        let mut scratch = self.ledger.clone();
+        let mut transactions: Vec<Transaction> = transaction_pool
+            .pool
+            .iter()
+            .filter(|tx| scratch.apply_transaction(tx).is_ok())
+            .cloned()
+            .collect();
+        let total_fee = Amount::checked_sum(transactions.iter().map(|tx| tx.fee)).unwrap_or(Amount::MAX);
+        let reward = calculations::calculate_mining_reward(height as u64, total_fee).base_units();
+        let timestamp = chrono::Utc::now().timestamp() as u64;
+
+        let total_weight: u128 = payouts.iter().map(|(_, weight)| *weight as u128).sum();
+        let mut remaining = reward;
+        for (position, (address, weight)) in payouts.iter().enumerate() {
+            let share = match position + 1 == payouts.len() {
+                true => remaining,
+                false => (reward as u128 * *weight as u128 / total_weight) as u64,
+            };
+            remaining -= share;
+            if share == 0 {
+                continue;
+            }
+            let amount = Amount::from_base_units(share);
+            let reward_transaction = Transaction::reward(*address, amount, calculations::calculate_fee(amount), timestamp, height);
+            debug!("Reward transaction: {:?}", reward_transaction);
+            transactions.push(reward_transaction);
         }
 
-        info!("Adjusted difficulty to: {}", self.difficulty);
+        let mut block = Block::new(height, String::new(), prev_block.hash);
+        block.set_transactions(transactions);
+        block.difficulty = self.difficulty;
+        block
     }
 
-    pub async fn create_genesis_block(&mut self) -> Block {
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+    /// Adds a block we mined and brings the pool in line with the new tip.
+    pub async fn submit_block(&mut self, block: Block, transaction_pool: &mut TransactionPool) -> Result<ChainUpdate, ChainError> {
+        let update = self.add_block(block).await?;
+        transaction_pool.apply_update(&update, &self.ledger);
+        if update.connected.is_empty() {
+            return Ok(update);
+        }
+        info!("Block mined and transactions added to the chain");
+        Ok(update)
+    }
+
+    pub async fn create_genesis_block(&mut self) -> Result<Block, ChainError> {
         let mut genesis_block = Block {
             index: 0,
-            timestamp,
+            timestamp: GENESIS_TIMESTAMP,
             data: "Genesis Block".to_string(),
-            prev_hash: "0".to_string(),
-            hash: String::new(),
+            prev_hash: BlockID::default(),
+            hash: BlockID::default(),
+            merkle_root: merkle::merkle_root(&[]),
             nonce: 0,
             transactions: vec![],
             difficulty: self.difficulty,
         };
         genesis_block.hash = Hashing::new(genesis_block.clone()).calculate_hash();
 
-        self.db.insert_block(genesis_block.clone()).await.unwrap();
-        genesis_block
+        self.db.commit_block(&genesis_block, &[]).await?;
+        Ok(genesis_block)
     }
 
-    pub async fn add_block(&mut self, mut block: Block) -> bool {
-        let prev_block = self.chain.last().unwrap();
-        assert_eq!(block.prev_hash, prev_block.hash);
+    /// Adds a block whose parent we know, however it reached us. A block on
+    /// the tip is validated against the current balances, committed together
+    /// with the cached balances it touched and extends the chain, and the
+    /// difficulty is recomputed for the next one. Any other block goes on a
+    /// side branch, and once that branch has more cumulative work than the
+    /// main chain we reorganize onto it.
+    pub async fn add_block(&mut self, block: Block) -> Result<ChainUpdate, ChainError> {
+        let tip = self.chain.last().ok_or(ChainError::Empty)?;
+        if self.tree.contains(&block.hash) {
+            return Err(ChainError::Duplicate { index: block.index });
+        }
+        if block.prev_hash != tip.hash {
+            return self.add_side_block(block).await;
+        }
+        let difficulty = self.expected_difficulty(&tip.hash);
+        Self::validate_block(tip, &block, difficulty)?;
+
+        let mut ledger = self.ledger.clone();
+        let touched = ledger
+            .apply_block(&block)
+            .map_err(|source| ChainError::Ledger { index: block.index, source })?;
 
-        block.index = prev_block.index + 1;
-        block.timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-        block.prev_hash = prev_block.hash.clone();
+        self.db.commit_block(&block, &touched).await?;
+        self.ledger = ledger;
+        self.tree.insert(&block, difficulty, true);
 
-        let mut hasher = Hashing::new(block.clone());
-        hasher.mine_block(self.difficulty);
-        block.hash = hasher.block.hash.clone();
+        info!("Block added: {:?}", block);
+        self.chain.push(block.clone());
+        self.difficulty = calculations::calculate_difficulty(&self.chain);
+        Ok(ChainUpdate { disconnected: vec![], connected: vec![block] })
+    }
+
+    async fn add_side_block(&mut self, block: Block) -> Result<ChainUpdate, ChainError> {
+        let parent = self
+            .block_by_hash(&block.prev_hash)
+            .ok_or(ChainError::UnknownParent { index: block.index, prev_hash: block.prev_hash })?;
+        let difficulty = self.expected_difficulty(&parent.hash);
+        Self::validate_block(parent, &block, difficulty)?;
+
+        let tip = self.chain.last().ok_or(ChainError::Empty)?;
+        let tip_work = self.tree.work(&tip.hash).unwrap_or_default();
+        let work = self.tree.insert(&block, difficulty, false);
+        if work <= tip_work {
+            info!("Block {} {} added to a side branch", block.index, block.hash);
+            return Ok(ChainUpdate::default());
+        }
+        self.reorganize(block.hash).await
+    }
+
+    /// Switches the main chain to the side branch ending at `new_tip`. The
+    /// balances are replayed from genesis up to the fork point and then
+    /// through the branch; if a branch block doesn't apply, the branch is
+    /// dropped and the main chain stays as it is. The store is rewritten
+    /// before anything in memory changes, so a failed write leaves the
+    /// branch on the side.
+    async fn reorganize(&mut self, new_tip: BlockID) -> Result<ChainUpdate, ChainError> {
+        let mut branch = vec![];
+        let mut hash = new_tip;
+        while let Some(block) = self.tree.side_block(&hash) {
+            hash = block.prev_hash;
+            branch.push(block.clone());
+        }
+        branch.reverse();
+        let fork = branch[0].index as usize - 1;
 
-        self.db.insert_block(block.clone()).await.unwrap();
+        let mut ledger = LedgerState::from_chain(&self.chain[..=fork])?;
+        for block in &branch {
+            if let Err(source) = ledger.apply_block(block) {
+                self.tree.remove_branch(&block.hash);
+                return Err(ChainError::Ledger { index: block.index, source });
+            }
+        }
+
+        let balances = self.ledger.changed_balances(&ledger);
+        self.db.replace_blocks(fork as u32 + 1, &branch, &balances).await?;
+        let disconnected = self.chain.split_off(fork + 1);
+        self.chain.extend(branch.iter().cloned());
+        self.tree.reorganize(&disconnected, &branch);
+        self.ledger = ledger;
+        self.difficulty = calculations::calculate_difficulty(&self.chain);
+
+        warn!(
+            "Reorganized at block {}: disconnected {} blocks, connected {} up to block {}",
+            fork,
+            disconnected.len(),
+            branch.len(),
+            self.chain.len() - 1
+        );
+        Ok(ChainUpdate { disconnected, connected: branch })
+    }
+
+    /// A main chain or side branch block by hash.
+    pub fn block_by_hash(&self, hash: &BlockID) -> Option<&Block> {
+        if !self.tree.contains(hash) {
+            return None;
+        }
+        self.tree.side_block(hash).or_else(|| self.chain.iter().rev().find(|block| block.hash == *hash))
+    }
+
+    /// Up to `count` blocks ending at `hash`, oldest first, following a side
+    /// branch back onto the main chain.
+    fn ancestors(&self, hash: &BlockID, count: usize) -> Vec<&Block> {
+        let mut ancestors = vec![];
+        let mut hash = *hash;
+        while ancestors.len() < count {
+            if let Some(block) = self.tree.side_block(&hash) {
+                hash = block.prev_hash;
+                ancestors.push(block);
+                continue;
+            }
+            if let Some(position) = self.chain.iter().rposition(|block| block.hash == hash) {
+                let start = (position + 1).saturating_sub(count - ancestors.len());
+                ancestors.extend(self.chain[start..=position].iter().rev());
+            }
+            break;
+        }
+        ancestors.reverse();
+        ancestors
+    }
+
+    /// The difficulty a block on top of `parent` must have.
+    pub fn expected_difficulty(&self, parent: &BlockID) -> u32 {
+        calculations::calculate_difficulty(&self.ancestors(parent, calculations::DIFFICULTY_ADJUSTMENT_INTERVAL))
+    }
+
+    /// The headers `validate_headers` needs to check headers on top of
+    /// `parent`.
+    pub fn ancestor_headers(&self, parent: &BlockID) -> Vec<BlockHeader> {
+        self.ancestors(parent, calculations::DIFFICULTY_ADJUSTMENT_INTERVAL)
+            .into_iter()
+            .map(BlockHeader::from_block)
+            .collect()
+    }
+
+    /// Checks that `block` correctly extends `prev_block` with
+    /// `expected_difficulty`, the difficulty its ancestors call for.
+    pub fn validate_block(prev_block: &Block, block: &Block, expected_difficulty: u32) -> Result<(), ChainError> {
+        let index = block.index;
+        let expected_index = prev_block.index + 1;
+        if index != expected_index {
+            return Err(ChainError::IndexMismatch { index, expected: expected_index });
+        }
+        if block.prev_hash != prev_block.hash {
+            return Err(ChainError::PrevHashMismatch {
+                index,
+                expected: prev_block.hash,
+                found: block.prev_hash,
+            });
+        }
+        if block.timestamp < prev_block.timestamp {
+            return Err(ChainError::TimestampRegression {
+                index,
+                timestamp: block.timestamp,
+                prev_timestamp: prev_block.timestamp,
+            });
+        }
+
+        if block.difficulty != expected_difficulty {
+            return Err(ChainError::DifficultyMismatch { index, expected: expected_difficulty, found: block.difficulty });
+        }
+
+        let expected_hash = Hashing::new(block.clone()).calculate_hash();
+        if block.hash != expected_hash {
+            return Err(ChainError::HashMismatch { index, expected: expected_hash, found: block.hash });
+        }
+        if !Hashing::meets_difficulty(&block.hash, block.difficulty) {
+            return Err(ChainError::InsufficientWork { index, difficulty: block.difficulty });
+        }
+
+        Self::validate_transactions(block)
+    }
+
+    /// Checks that `headers` form a chain on top of `ancestors`, the
+    /// headers from `ancestor_headers` ending at their parent: consecutive
+    /// indexes, linked hashes, no timestamp regressions, the expected
+    /// difficulty and enough work. Transactions can only be checked once the
+    /// block bodies arrive.
+    pub fn validate_headers(ancestors: &[BlockHeader], headers: &[BlockHeader]) -> Result<(), ChainError> {
+        let mut window = ancestors.to_vec();
+        let mut prev = ancestors.last().ok_or(ChainError::Empty)?;
+        let mut prev_hash = prev.hash();
+        for header in headers {
+            let index = header.index;
+            if index != prev.index + 1 {
+                return Err(ChainError::IndexMismatch { index, expected: prev.index + 1 });
+            }
+            if header.prev_hash != prev_hash {
+                return Err(ChainError::PrevHashMismatch { index, expected: prev_hash, found: header.prev_hash });
+            }
+            if header.timestamp < prev.timestamp {
+                return Err(ChainError::TimestampRegression {
+                    index,
+                    timestamp: header.timestamp,
+                    prev_timestamp: prev.timestamp,
+                });
+            }
+            let expected = calculations::calculate_difficulty(&window);
+            if header.difficulty != expected {
+                return Err(ChainError::DifficultyMismatch { index, expected, found: header.difficulty });
+            }
+            let hash = header.hash();
+            if !Hashing::meets_difficulty(&hash, header.difficulty) {
+                return Err(ChainError::InsufficientWork { index, difficulty: header.difficulty });
+            }
+            if window.len() == calculations::DIFFICULTY_ADJUSTMENT_INTERVAL {
+                window.remove(0);
+            }
+            window.push(header.clone());
+            prev_hash = hash;
+            prev = header;
+        }
+        Ok(())
+    }
+
+    /// Hashes walking back from the tip, one by one for the newest ten and
+    /// then doubling the step, always ending with genesis. A peer answers
+    /// from the newest one it knows, which is where our chains agree.
+    pub fn locator(&self) -> Vec<BlockID> {
+        let mut locator = vec![];
+        let mut position = self.chain.len() - 1;
+        let mut step = 1;
+        while position > 0 {
+            locator.push(self.chain[position].hash);
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            position = position.saturating_sub(step);
+        }
+        locator.push(self.chain[0].hash);
+        locator
+    }
 
-        info!("Block mined: {:?}", block);
-        self.chain.push(block);
-        true
+    /// Up to `limit` headers following the newest `locator` hash in our
+    /// chain, or following genesis if none of them are.
+    pub fn headers_after(&self, locator: &[BlockID], limit: usize) -> Vec<BlockHeader> {
+        let start = locator
+            .iter()
+            .find_map(|hash| self.chain.iter().position(|block| block.hash == *hash))
+            .unwrap_or_default();
+        self.chain.iter().skip(start + 1).take(limit).map(BlockHeader::from_block).collect()
     }
 
-    pub async fn load_blocks(&mut self) {
-        let blocks = self.db.get_blocks().await.unwrap();
+    /// Validates every block in the chain, starting from genesis, and returns
+    /// the balances that result from replaying it.
+    pub fn validate_chain(&self) -> Result<LedgerState, ChainError> {
+        Self::validate_blocks(&self.chain)
+    }
+
+    /// Like `validate_chain`, for blocks that aren't loaded into a
+    /// `Blockchain`.
+    pub fn validate_blocks(chain: &[Block]) -> Result<LedgerState, ChainError> {
+        let genesis = chain.first().ok_or(ChainError::Empty)?;
+        Self::validate_genesis(genesis)?;
+
+        for position in 1..chain.len() {
+            Self::validate_block(&chain[position - 1], &chain[position], Self::difficulty_at(chain, position))?;
+        }
+        LedgerState::from_chain(chain)
+    }
+
+    /// Compares the `WALLETS` cache with the ledger and returns every address
+    /// whose cached balance is wrong, with the cached and expected values.
+    pub async fn audit_wallet_cache(&self) -> Vec<(Address, Amount, Amount)> {
+        let cached: HashMap<Address, Amount> = self.db.get_balances().await.unwrap_or_default().into_iter().collect();
+
+        let mut addresses: Vec<&Address> = cached.keys().chain(self.ledger.balances().keys()).collect();
+        addresses.sort();
+        addresses.dedup();
+        addresses
+            .into_iter()
+            .filter_map(|address| {
+                let cached = cached.get(address).copied().unwrap_or_default();
+                let expected = self.ledger.balance(address);
+                (cached != expected).then_some((*address, cached, expected))
+            })
+            .collect()
+    }
+
+    /// Overwrites the `WALLETS` cache with the balances derived from the chain.
+    pub async fn rebuild_wallet_cache(&self) -> Result<(), StoreError> {
+        for (address, cached, expected) in self.audit_wallet_cache().await {
+            debug!("Rebuilding cached balance of {}: {} -> {}", address, cached, expected);
+            self.db.update_balance(&address, expected).await?;
+        }
+        Ok(())
+    }
+
+    /// Reloads every stored block, validates it from genesis, then wipes and
+    /// rebuilds the stored transactions and cached balances from the blocks.
+    /// Nothing is deleted unless the whole chain validates.
+    pub async fn reindex(&mut self) -> Result<ReindexReport, ReindexError> {
+        let blocks = self.db.get_blocks().await?;
+        let genesis = blocks.first().ok_or(ChainError::Empty)?;
+        Self::validate_genesis(genesis)?;
+
+        info!("Reindexing {} blocks", blocks.len());
+        let mut ledger = LedgerState::default();
+        for (position, block) in blocks.iter().enumerate() {
+            if position > 0 {
+                Self::validate_block(&blocks[position - 1], block, Self::difficulty_at(&blocks, position))?;
+            }
+            ledger
+                .apply_block(block)
+                .map_err(|source| ChainError::Ledger { index: block.index, source })?;
+            if (position + 1) % REINDEX_LOG_INTERVAL == 0 {
+                info!("Validated {}/{} blocks", position + 1, blocks.len());
+            }
+        }
+
+        info!("Chain is valid, rebuilding transactions and balances");
+        self.db.clear_derived().await?;
+        let mut transactions = 0;
+        for (position, block) in blocks.iter().enumerate() {
+            self.db.index_transactions(&block.transactions).await?;
+            transactions += block.transactions.len();
+            if (position + 1) % REINDEX_LOG_INTERVAL == 0 {
+                info!("Indexed transactions of {}/{} blocks", position + 1, blocks.len());
+            }
+        }
+        for (address, balance) in ledger.balances() {
+            self.db.update_balance(address, *balance).await?;
+        }
+
+        let report = ReindexReport { blocks: blocks.len(), transactions, wallets: ledger.balances().len() };
+        info!("Reindexed {} blocks, {} transactions and {} wallets", report.blocks, report.transactions, report.wallets);
+        self.difficulty = calculations::calculate_difficulty(&blocks);
+        self.tree = BlockTree::from_chain(&blocks);
+        self.chain = blocks;
+        self.ledger = ledger;
+        Ok(report)
+    }
+
+    /// The difficulty `chain[position]` must have.
+    fn difficulty_at(chain: &[Block], position: usize) -> u32 {
+        calculations::calculate_difficulty(&chain[position.saturating_sub(calculations::DIFFICULTY_ADJUSTMENT_INTERVAL)..position])
+    }
+
+    pub fn validate_genesis(block: &Block) -> Result<(), ChainError> {
+        if block.index != 0 || block.prev_hash != BlockID::default() {
+            return Err(ChainError::InvalidGenesis { index: block.index });
+        }
+        let expected_hash = Hashing::new(block.clone()).calculate_hash();
+        if block.hash != expected_hash {
+            return Err(ChainError::HashMismatch { index: block.index, expected: expected_hash, found: block.hash });
+        }
+        Self::validate_transactions(block)
+    }
+
+    /// Checks the merkle root and each transaction on its own. A repeated
+    /// transaction ID is rejected here: the merkle tree pairs an odd last
+    /// node with itself, so a block padded with a copy of its last
+    /// transaction has the same root and hash as the honest one.
+    fn validate_transactions(block: &Block) -> Result<(), ChainError> {
+        let expected_root = block.compute_merkle_root();
+        if block.merkle_root != expected_root {
+            return Err(ChainError::MerkleRootMismatch {
+                index: block.index,
+                expected: expected_root,
+                found: block.merkle_root,
+            });
+        }
+        let mut ids = HashSet::new();
+        for (position, transaction) in block.transactions.iter().enumerate() {
+            let id = transaction.id();
+            if !ids.insert(id) {
+                return Err(ChainError::DuplicateTransaction { index: block.index, position, id });
+            }
+            transaction
+                .validate()
+                .map_err(|source| ChainError::InvalidTransaction { index: block.index, position, source })?;
+        }
+        Ok(())
+    }
+
+    /// Finds the block containing `tx_id` and returns its index with an
+    /// inclusion proof against that block's `merkle_root`.
+    pub fn merkle_proof(&self, tx_id: &TransactionID) -> Option<(u32, MerkleProof)> {
+        self.chain
+            .iter()
+            .rev()
+            .find_map(|block| block.merkle_proof(tx_id).map(|proof| (block.index, proof)))
+    }
+
+    pub async fn load_blocks(&mut self) -> Result<(), ChainError> {
+        let blocks = self.db.get_blocks().await?;
         if blocks.is_empty() {
-            let genesis_block = self.create_genesis_block().await;
+            let genesis_block = self.create_genesis_block().await?;
             self.chain.push(genesis_block);
         } else {
             self.chain.extend(blocks);
         }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::amount::Amount;
+    use crate::blockchain::db::memory::core::MemoryStore;
+    use crate::blockchain::hashing::CancelToken;
+    use crate::blockchain::test_util::mine_block;
+
+    fn genesis() -> Block {
+        let mut block = Block::new(0, "Genesis Block".to_string(), BlockID::default());
+        block.nonce = 0;
+        block.hash = Hashing::new(block.clone()).calculate_hash();
+        block
+    }
+
+    fn mine_next(prev_block: &Block, data: &str) -> Block {
+        let block = Block::new(prev_block.index + 1, data.to_string(), prev_block.hash);
+        let mut hasher = Hashing::new(block);
+        hasher.mine_block(1, &CancelToken::new(), None).unwrap();
+        hasher.block
+    }
+
+    #[test]
+    fn accepts_mined_block() {
+        let genesis = genesis();
+        let block = mine_next(&genesis, "first");
+        assert!(Blockchain::validate_genesis(&genesis).is_ok());
+        assert!(Blockchain::validate_block(&genesis, &block, 1).is_ok());
+    }
+
+    #[test]
+    fn validates_header_chains() {
+        let genesis = genesis();
+        let first = mine_next(&genesis, "first");
+        let second = mine_next(&first, "second");
+        let headers = vec![BlockHeader::from_block(&first), BlockHeader::from_block(&second)];
+        let prev = vec![BlockHeader::from_block(&genesis)];
+        assert!(Blockchain::validate_headers(&prev, &headers).is_ok());
+
+        let mut skipped = headers.clone();
+        skipped.remove(0);
+        assert!(matches!(
+            Blockchain::validate_headers(&prev, &skipped),
+            Err(ChainError::IndexMismatch { index: 2, expected: 1 })
+        ));
+        let mut tampered = headers.clone();
+        tampered[0].nonce += 1;
+        assert!(Blockchain::validate_headers(&prev, &tampered).is_err());
+    }
+
+    #[test]
+    fn rejects_unexpected_difficulty() {
+        let genesis = genesis();
+        let block = mine_next(&genesis, "first");
+
+        // Every hash meets difficulty 0, so it must not be accepted just
+        // because the block claims it.
+        let mut easy = Block::new(1, "easy".to_string(), genesis.hash);
+        easy.hash = Hashing::new(easy.clone()).calculate_hash();
+        assert!(matches!(
+            Blockchain::validate_block(&genesis, &easy, 1),
+            Err(ChainError::DifficultyMismatch { index: 1, expected: 1, found: 0 })
+        ));
+        assert!(matches!(
+            Blockchain::validate_headers(&[BlockHeader::from_block(&genesis)], &[BlockHeader::from_block(&easy)]),
+            Err(ChainError::DifficultyMismatch { index: 1, expected: 1, found: 0 })
+        ));
+
+        let mut second = Block::new(2, "easy".to_string(), block.hash);
+        second.hash = Hashing::new(second.clone()).calculate_hash();
+        let headers = [BlockHeader::from_block(&block), BlockHeader::from_block(&second)];
+        assert!(matches!(
+            Blockchain::validate_headers(&[BlockHeader::from_block(&genesis)], &headers),
+            Err(ChainError::DifficultyMismatch { index: 2, expected: 1, found: 0 })
+        ));
+    }
+
+    #[test]
+    fn rejects_tampered_blocks() {
+        let genesis = genesis();
+        let block = mine_next(&genesis, "first");
+
+        let mut wrong_index = block.clone();
+        wrong_index.index = 5;
+        assert!(matches!(
+            Blockchain::validate_block(&genesis, &wrong_index, 1),
+            Err(ChainError::IndexMismatch { index: 5, expected: 1 })
+        ));
+
+        let mut wrong_link = block.clone();
+        wrong_link.prev_hash = BlockID::from([0xde; 32]);
+        assert!(matches!(
+            Blockchain::validate_block(&genesis, &wrong_link, 1),
+            Err(ChainError::PrevHashMismatch { index: 1, .. })
+        ));
+
+        let mut tampered = block.clone();
+        tampered.nonce = tampered.nonce.wrapping_add(1);
+        assert!(matches!(
+            Blockchain::validate_block(&genesis, &tampered, 1),
+            Err(ChainError::HashMismatch { index: 1, .. })
+        ));
+
+        let mut extra_transaction = block.clone();
+        extra_transaction
+            .transactions
+            .push(Transaction::new(Address::default(), Address::default(), Amount::from_coins(1), 0, Amount::ZERO));
+        assert!(matches!(
+            Blockchain::validate_block(&genesis, &extra_transaction, 1),
+            Err(ChainError::MerkleRootMismatch { index: 1, .. })
+        ));
+
+        let mut backdated = block;
+        backdated.timestamp = genesis.timestamp - 1;
+        assert!(matches!(
+            Blockchain::validate_block(&genesis, &backdated, 1),
+            Err(ChainError::TimestampRegression { index: 1, .. })
+        ));
+    }
+
+    fn signed(signing_key: &ed25519_dalek::SigningKey, receiver: Address, amount: Amount) -> Transaction {
+        let mut transaction = Transaction::new(Address::default(), receiver, amount, 0, calculations::calculate_fee(amount));
+        transaction.sign(signing_key);
+        transaction
+    }
+
+    #[test]
+    fn ledger_applies_rewards_and_transfers() {
+        let alice_key = ed25519_dalek::SigningKey::from_bytes(&[1; 32]);
+        let alice = Address::from_public_key(&alice_key.verifying_key());
+        let bob = Address::from([2; 20]);
+        let reward = calculations::calculate_mining_reward(1, Amount::ZERO);
+
+        let mut block = Block::new(1, String::new(), BlockID::default());
+        block.set_transactions(vec![Transaction::reward(alice, reward, Amount::ZERO, 0, 1)]);
+        let mut ledger = LedgerState::default();
+        ledger.apply_block(&block).unwrap();
+        assert_eq!(ledger.balance(&alice), reward);
+
+        let amount = Amount::from_base_units(reward.base_units() / 2);
+        let transfer = signed(&alice_key, bob, amount);
+        let fee = transfer.fee;
+        ledger.apply_transaction(&transfer).unwrap();
+        assert_eq!(ledger.balance(&bob), amount);
+        assert_eq!(Some(ledger.balance(&alice)), reward.checked_sub(amount).and_then(|rest| rest.checked_sub(fee)));
+
+        let before = ledger.clone();
+        let overspend = signed(&alice_key, bob, reward);
+        assert!(matches!(ledger.apply_transaction(&overspend), Err(LedgerError::InsufficientBalance { .. })));
+        assert_eq!(ledger.balances(), before.balances());
+    }
+
+    #[test]
+    fn ledger_rejects_oversized_rewards() {
+        let miner = Address::from([3; 20]);
+        let allowed = calculations::calculate_mining_reward(1, Amount::ZERO);
+        let greedy = allowed.checked_add(Amount::from_base_units(1)).unwrap();
+
+        let mut block = Block::new(1, String::new(), BlockID::default());
+        block.set_transactions(vec![Transaction::reward(miner, greedy, Amount::ZERO, 0, 1)]);
+        let mut ledger = LedgerState::default();
+        assert_eq!(ledger.apply_block(&block), Err(LedgerError::ExcessiveReward { allowed, found: greedy }));
+        assert_eq!(ledger.balance(&miner), Amount::ZERO);
+
+        block.set_transactions(vec![Transaction::reward(miner, allowed, Amount::ZERO, 0, 7)]);
+        assert!(matches!(ledger.apply_block(&block), Err(LedgerError::RewardHeightMismatch { expected: 1, .. })));
+    }
+
+    #[test]
+    fn ledger_rejects_replayed_transactions() {
+        let alice_key = ed25519_dalek::SigningKey::from_bytes(&[1; 32]);
+        let alice = Address::from_public_key(&alice_key.verifying_key());
+        let reward = calculations::calculate_mining_reward(1, Amount::ZERO);
+        let mut funding = Block::new(1, String::new(), BlockID::default());
+        funding.set_transactions(vec![Transaction::reward(alice, reward, Amount::ZERO, 0, 1)]);
+        let mut ledger = LedgerState::default();
+        ledger.apply_block(&funding).unwrap();
+
+        // Alice can afford the transfer twice, but it may only be applied once.
+        let transfer = signed(&alice_key, Address::from([2; 20]), Amount::from_base_units(reward.base_units() / 4));
+        let mut block = Block::new(2, String::new(), BlockID::default());
+        block.set_transactions(vec![transfer.clone()]);
+        ledger.apply_block(&block).unwrap();
+        assert!(ledger.is_confirmed(&transfer.id()));
+
+        let before = ledger.clone();
+        let mut replay = Block::new(3, String::new(), BlockID::default());
+        replay.set_transactions(vec![transfer.clone()]);
+        assert_eq!(ledger.apply_block(&replay), Err(LedgerError::AlreadyConfirmed(transfer.id())));
+        assert_eq!(ledger.apply_transaction(&transfer), Err(LedgerError::AlreadyConfirmed(transfer.id())));
+        assert_eq!(ledger.balances(), before.balances());
+    }
+
+    #[test]
+    fn ledger_rejects_transactions_listed_twice_in_a_block() {
+        let alice_key = ed25519_dalek::SigningKey::from_bytes(&[1; 32]);
+        let alice = Address::from_public_key(&alice_key.verifying_key());
+        let reward = calculations::calculate_mining_reward(1, Amount::ZERO);
+        let mut funding = Block::new(1, String::new(), BlockID::default());
+        funding.set_transactions(vec![Transaction::reward(alice, reward, Amount::ZERO, 0, 1)]);
+        let mut ledger = LedgerState::default();
+        ledger.apply_block(&funding).unwrap();
+
+        let transfer = signed(&alice_key, Address::from([2; 20]), Amount::from_base_units(reward.base_units() / 4));
+        let mut block = Block::new(2, String::new(), BlockID::default());
+        block.set_transactions(vec![transfer.clone(), transfer.clone()]);
+        let before = ledger.clone();
+        assert_eq!(ledger.apply_block(&block), Err(LedgerError::DuplicateInBlock(transfer.id())));
+        assert_eq!(ledger.balances(), before.balances());
+        assert!(!ledger.is_confirmed(&transfer.id()));
+    }
+
+    #[tokio::test]
+    async fn mines_and_reloads_from_store() {
+        let store: Store = Arc::new(MemoryStore::new());
+        let mut blockchain = Blockchain::new(store.clone()).await.unwrap();
+        let mut pool = TransactionPool::new(store.clone());
+        let miner = Address::from([4; 20]);
+        mine_block(&mut blockchain, &mut pool, &miner).await;
+
+        let balance = blockchain.ledger.balance(&miner);
+        assert_eq!(blockchain.chain.len(), 2);
+        assert!(!balance.is_zero());
+        assert_eq!(store.get_balance(&miner).await.unwrap(), balance);
+
+        let reloaded = Blockchain::new(store).await.unwrap();
+        assert_eq!(reloaded.chain, blockchain.chain);
+        assert_eq!(reloaded.ledger.balance(&miner), balance);
+    }
+
+    #[tokio::test]
+    async fn rejects_blocks_padded_with_a_repeated_transaction() {
+        let store: Store = Arc::new(MemoryStore::new());
+        let mut blockchain = Blockchain::new(store.clone()).await.unwrap();
+        let mut pool = TransactionPool::new(store);
+        let alice_key = ed25519_dalek::SigningKey::from_bytes(&[2; 32]);
+        let alice = Address::from_public_key(&alice_key.verifying_key());
+        mine_block(&mut blockchain, &mut pool, &alice).await;
+
+        let amount = Amount::from_base_units(blockchain.ledger.balance(&alice).base_units() / 4);
+        for receiver in [3, 4] {
+            pool.add_transaction(signed(&alice_key, Address::from([receiver; 20]), amount), &blockchain.ledger).await.unwrap();
+        }
+        let mut hasher = Hashing::new(blockchain.block_template(&pool, &Address::from([5; 20])));
+        hasher.mine_block(blockchain.difficulty, &CancelToken::new(), None).unwrap();
+        let block = hasher.block;
+        assert_eq!(block.transactions.len(), 3);
+
+        // [reward, a, b] and [reward, a, b, b] share the merkle root and hash.
+        let mut padded = block.clone();
+        padded.transactions.push(block.transactions[2].clone());
+        assert_eq!(padded.compute_merkle_root(), block.merkle_root);
+        assert_eq!(Hashing::new(padded.clone()).calculate_hash(), block.hash);
+
+        let err = blockchain.add_block(padded).await.unwrap_err();
+        assert!(matches!(err, ChainError::DuplicateTransaction { index: 2, position: 3, .. }));
+        assert!(err.is_invalid_data());
+        blockchain.add_block(block).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn recomputes_difficulty_for_added_blocks() {
+        let mut blockchain = Blockchain::new(Arc::new(MemoryStore::new())).await.unwrap();
+        for _ in 0..12 {
+            let tip = blockchain.chain.last().unwrap();
+            let mut hasher = Hashing::new(Block::new(tip.index + 1, String::new(), tip.hash));
+            hasher.mine_block(blockchain.difficulty, &CancelToken::new(), None).unwrap();
+            blockchain.add_block(hasher.block).await.unwrap();
+            assert_eq!(blockchain.difficulty, calculations::calculate_difficulty(&blockchain.chain));
+        }
+        assert!(blockchain.difficulty > 1);
+    }
+
+    #[tokio::test]
+    async fn reorganizes_onto_the_heaviest_branch() {
+        let store: Store = Arc::new(MemoryStore::new());
+        let mut blockchain = Blockchain::new(store.clone()).await.unwrap();
+        let mut pool = TransactionPool::new(store.clone());
+        let rival_store: Store = Arc::new(MemoryStore::new());
+        let mut rival = Blockchain::new(rival_store.clone()).await.unwrap();
+        let mut rival_pool = TransactionPool::new(rival_store);
+
+        // Both chains share block 1, which pays `alice`.
+        let alice_key = ed25519_dalek::SigningKey::from_bytes(&[2; 32]);
+        let alice = Address::from_public_key(&alice_key.verifying_key());
+        mine_block(&mut blockchain, &mut pool, &alice).await;
+        rival.add_block(blockchain.chain[1].clone()).await.unwrap();
+
+        // Our block 2 includes a payment from `alice`, the rival's doesn't.
+        let amount = Amount::from_base_units(blockchain.ledger.balance(&alice).base_units() / 2);
+        let mut payment = Transaction::new(alice, Address::from([3; 20]), amount, GENESIS_TIMESTAMP, calculations::calculate_fee(amount));
+        payment.sign(&alice_key);
+        pool.add_transaction(payment.clone(), &blockchain.ledger).await.unwrap();
+        mine_block(&mut blockchain, &mut pool, &Address::from([4; 20])).await;
+        mine_block(&mut rival, &mut rival_pool, &Address::from([5; 20])).await;
+        mine_block(&mut rival, &mut rival_pool, &Address::from([5; 20])).await;
+
+        let side = blockchain.add_block(rival.chain[2].clone()).await.unwrap();
+        assert_eq!(side, ChainUpdate::default());
+        assert_eq!(blockchain.chain.len(), 3);
+        assert!(blockchain.block_by_hash(&rival.chain[2].hash).is_some());
+
+        let ours = blockchain.chain[2].clone();
+        let update = blockchain.add_block(rival.chain[3].clone()).await.unwrap();
+        assert_eq!(update.disconnected, vec![ours]);
+        assert_eq!(update.connected, rival.chain[2..].to_vec());
+        assert_eq!(blockchain.chain, rival.chain);
+        assert_eq!(store.get_blocks().await.unwrap(), rival.chain);
+        assert_eq!(blockchain.ledger.balances(), rival.ledger.balances());
+        assert_eq!(store.get_balance(&Address::from([4; 20])).await.unwrap(), Amount::ZERO);
+
+        pool.apply_update(&update, &blockchain.ledger);
+        assert_eq!(pool.pool, vec![payment]);
+        assert!(matches!(
+            blockchain.add_block(rival.chain[3].clone()).await,
+            Err(ChainError::Duplicate { index: 3 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn ignores_branches_claiming_less_difficulty() {
+        let store: Store = Arc::new(MemoryStore::new());
+        let mut blockchain = Blockchain::new(store.clone()).await.unwrap();
+        let mut pool = TransactionPool::new(store);
+        mine_block(&mut blockchain, &mut pool, &Address::from([4; 20])).await;
+
+        // A long branch of free blocks would outweigh the main chain if
+        // their work came from the difficulty they claim.
+        let mut prev = blockchain.chain[0].clone();
+        for index in 1..=4 {
+            let mut block = Block::new(index, String::new(), prev.hash);
+            block.hash = Hashing::new(block.clone()).calculate_hash();
+            let result = blockchain.add_block(block.clone()).await;
+            if index == 1 {
+                assert!(matches!(result, Err(ChainError::DifficultyMismatch { index: 1, expected: 1, found: 0 })));
+            } else {
+                assert!(matches!(result, Err(ChainError::UnknownParent { .. })));
+            }
+            prev = block;
+        }
+        assert_eq!(blockchain.chain.len(), 2);
+        assert!(!blockchain.tree.contains(&prev.hash));
+    }
+
+    #[tokio::test]
+    async fn reindex_rebuilds_derived_state() {
+        let store: Store = Arc::new(MemoryStore::new());
+        let mut blockchain = Blockchain::new(store.clone()).await.unwrap();
+        let mut pool = TransactionPool::new(store.clone());
+        let miner = Address::from([4; 20]);
+        mine_block(&mut blockchain, &mut pool, &miner).await;
+        mine_block(&mut blockchain, &mut pool, &miner).await;
+
+        let stray = Address::from([5; 20]);
+        store.clear_derived().await.unwrap();
+        store.update_balance(&stray, Amount::from_coins(1)).await.unwrap();
+
+        let report = blockchain.reindex().await.unwrap();
+        assert_eq!((report.blocks, report.transactions, report.wallets), (3, 2, 1));
+        assert_eq!(store.get_balance(&stray).await.unwrap(), Amount::ZERO);
+        assert_eq!(store.get_balance(&miner).await.unwrap(), blockchain.ledger.balance(&miner));
+        for transaction in &blockchain.chain[1].transactions {
+            assert_eq!(store.get_transaction(&transaction.id()).await.unwrap().as_ref(), Some(transaction));
+        }
     }
 }
@@ -1,158 +1,114 @@
-// use ecdsa::Error;
-// use r2d2::Pool;
-// use r2d2_sqlite::{rusqlite::params, SqliteConnectionManager};
-// use serde::Serialize;
-// use crate::blockchain::{block::Block, transaction::Transaction};
-// use crate::utils::calculations::calculate_fee;
-// use crate::blockchain::db::tables;
-
-// #[derive(Debug)]
-// pub struct Database {
-//     pub pool: Pool<SqliteConnectionManager>,
-// }
-
-// impl Serialize for Database {
-//     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-//     where
-//         S: serde::Serializer,
-//     {
-//         let conn = self.pool.get().expect("Failed to get connection.");
-//         let mut stmt = conn.prepare("SELECT COUNT(*) FROM blocks").expect("Failed to prepare query.");
-//         let count: i64 = stmt.query_row([], |row| row.get(0)).expect("Failed to query blocks count.");
-//         count.serialize(serializer)
-//     }
-// }
-
-// impl Clone for Database {
-//     fn clone(&self) -> Self {
-//         Database {
-//             pool: self.pool.clone(),
-//         }
-//     }
-// }
-
-// impl Database {
-//     pub fn new(db_path: &str) -> Database {
-//         let manager = SqliteConnectionManager::file(db_path);
-//         let pool = Pool::builder().build(manager).expect("Failed to create pool.");
-//         Database { pool }
-//     }
-
-
-//     pub fn create_tables(&self) -> Result<(), Error> {
-//         tables::create_blocks_table(self)?;
-//         tables::create_transaction_table(self)?;
-//         tables::create_wallet_table(self)?;
-//         Ok(())
-//     }
-
-//     pub async fn insert_block(&self, block: Block) -> Result<(), Error> {
-//         let conn = self.pool.get().expect("Failed to get connection.");
-//         conn.execute(
-//             "INSERT INTO blocks (\"index\", timestamp, data, prev_hash, hash, nonce) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
-//             params![block.index, block.timestamp, block.data, block.prev_hash, block.hash, block.nonce],
-//         ).expect("Failed to insert block.");
-        
-//         for transaction in block.transactions {
-//             self.insert_transaction(&transaction).expect("Failed to insert transaction.");
-//             // self.insert_transaction(&transaction);
-//         }
-        
-//         Ok(())
-//     }
-
-//     pub fn insert_transaction(&self, transaction: &Transaction) -> Result<(), Error> {
-//         let conn = self.pool.get().expect("Failed to get connection.");
-//         conn.execute(
-//             "INSERT INTO transactions (sender, receiver, amount, timestamp) VALUES (?1, ?2, ?3, ?4)",
-//             params![
-//                 transaction.sender,
-//                 transaction.receiver,
-//                 transaction.amount,
-//                 transaction.timestamp,
-//             ],
-//         ).expect("Failed to insert transaction.");
-//         Ok(())
-//     }
-
-//     pub fn remove_transaction(&self, transaction: &Transaction) -> Result<(), Error> {
-//         let conn = self.pool.get().expect("Failed to get connection.");
-//         conn.execute(
-//             "DELETE FROM transactions WHERE sender = ?1 AND receiver = ?2 AND amount = ?3 AND timestamp = ?4",
-//             params![
-//                 transaction.sender,
-//                 transaction.receiver,
-//                 transaction.amount,
-//                 transaction.timestamp,
-//             ],
-//         ).expect("Failed to remove transaction.");
-//         Ok(())
-//     }
-
-//     pub fn get_balance(&self, address: &str) -> Result<f64, Error> {
-//         let conn = self.pool.get().expect("Failed to get connection.");
-//         let mut stmt = conn.prepare("SELECT balance FROM wallets WHERE address = ?1").expect("Failed to prepare query.");
-//         let balance: f64 = stmt.query_row(params![address], |row| row.get(0)).unwrap_or(0.0);
-//         Ok(balance)
-//     }
-
-//     pub fn update_balance(&self, address: &str, balance: f64) -> Result<(), Error> {
-//         let conn = self.pool.get().expect("Failed to get connection.");
-//         conn.execute(
-//             "INSERT OR REPLACE INTO wallets (address, balance) VALUES (?1, ?2)",
-//             params![address, balance],
-//         ).expect("Failed to update balance.");
-//         Ok(())
-//     }
-
-//     pub fn get_transactions(&self) -> Result<Vec<Transaction>, Error> {
-//         let conn = self.pool.get().expect("Failed to get connection.");
-//         let mut stmt = conn.prepare("SELECT sender, receiver, amount, timestamp FROM transactions").expect("Failed to prepare query.");
-//         let tx_iter = stmt.query_map([], |row| {
-//             Ok(Transaction {
-//                 sender: row.get(0)?,
-//                 receiver: row.get(1)?,
-//                 amount: row.get(2)?,
-//                 timestamp: row.get(3)?,
-//                 fee: calculate_fee(row.get(2)?),
-//             })
-//         }).expect("Failed to query transactions.");
-    
-//         let mut transactions = Vec::new();
-//         for tx in tx_iter {
-//             transactions.push(tx.expect("Failed to get transaction."));
-//         }
-//         Ok(transactions)
-//     }
-
-//     pub fn get_blocks(&self) -> Result<Vec<Block>, Error> {
-//         let conn = self.pool.get().expect("Failed to get connection.");
-//         let mut stmt = conn.prepare("SELECT \"index\", timestamp, data, prev_hash, hash, nonce, transactions FROM blocks")
-//             .expect("Failed to prepare query.");
-    
-//         let block_iter = stmt.query_map([], |row| {    
-//             let transactions_json: Option<String> = row.get(6)?;
-    
-//             let transactions: Vec<Transaction> = match transactions_json {
-//                 Some(json) => serde_json::from_str(&json).expect("Failed to deserialize transactions"),
-//                 None => vec![],
-//             };
-    
-//             Ok(Block {
-//                 index: row.get(0)?,
-//                 timestamp: row.get(1)?,
-//                 data: row.get(2)?,
-//                 prev_hash: row.get(3)?,
-//                 hash: row.get(4)?,
-//                 nonce: row.get(5)?,
-//                 transactions,
-//             })
-//         }).expect("Failed to query blocks.");
-    
-//         let mut blocks = Vec::new();
-//         for block in block_iter {
-//             blocks.push(block.expect("Failed to get block."));
-//         }
-//         Ok(blocks)
-//     }
-// }
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use log::info;
+use r2d2_sqlite::rusqlite;
+use thiserror::Error;
+
+use crate::blockchain::amount::Amount;
+use crate::blockchain::block::Block;
+use crate::blockchain::db::memory::core::MemoryStore;
+use crate::blockchain::db::mongodb::core::MongoDB;
+use crate::blockchain::db::sqlite::core::SqliteStore;
+use crate::blockchain::p2p::peers::PeerRecord;
+use crate::blockchain::transaction::{Transaction, TransactionID};
+use crate::blockchain::wallet::Address;
+
+#[derive(Debug, Error)]
+pub enum StoreError {
+    #[error("mongodb error: {0}")]
+    Mongo(#[from] mongodb::error::Error),
+    #[error("sqlite error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("connection pool error: {0}")]
+    Pool(#[from] r2d2::Error),
+    #[error("{0} already exists")]
+    Duplicate(String),
+    #[error("corrupt record: {0}")]
+    Corrupt(String),
+    #[error("database schema version {found} is newer than the supported {supported}")]
+    UnsupportedSchema { found: i64, supported: i64 },
+    #[error("MongoDB is a standalone server, but blocks are written in transactions that need a replica set; start mongod with --replSet and run rs.initiate()")]
+    StandaloneMongo,
+    #[error("the stored chain was written by an incompatible version and can't be migrated ({0}); drop the SERENITY database, or point MONGODB_URI at a new one, to start a new chain")]
+    IncompatibleChain(String),
+    #[error("configuration error: {0}")]
+    Config(String),
+}
+
+pub type StoreResult<T> = Result<T, StoreError>;
+
+/// Persistent storage for blocks, transactions and the cached wallet
+/// balances. Balances are only a cache of the ledger derived from the blocks.
+#[async_trait]
+pub trait ChainStore: Send + Sync + Debug {
+    /// Creates whatever tables, collections and indexes the backend needs.
+    /// Safe to run more than once.
+    async fn migrate(&self) -> StoreResult<()>;
+
+    /// Atomically stores `block`, indexes its transactions and writes the
+    /// new cached `balances` of the addresses it touched. Either everything
+    /// is written or, on error, nothing is.
+    async fn commit_block(&self, block: &Block, balances: &[(Address, Amount)]) -> StoreResult<()>;
+
+    /// Atomically deletes every block from index `from` on and stores
+    /// `blocks` in their place, like `commit_block` does for one block.
+    /// Used to switch to a heavier branch. Transactions of the removed
+    /// blocks stay indexed.
+    async fn replace_blocks(&self, from: u32, blocks: &[Block], balances: &[(Address, Amount)]) -> StoreResult<()>;
+
+    /// Every stored block, in chain order.
+    async fn get_blocks(&self) -> StoreResult<Vec<Block>> {
+        self.get_blocks_range(0, u32::MAX).await
+    }
+
+    /// Stored blocks with `from <= index < to`, in chain order.
+    async fn get_blocks_range(&self, from: u32, to: u32) -> StoreResult<Vec<Block>>;
+
+    /// Fails with `StoreError::Duplicate` if a transaction with the same ID
+    /// is already stored.
+    async fn insert_transaction(&self, transaction: &Transaction) -> StoreResult<()>;
+
+    async fn get_transaction(&self, id: &TransactionID) -> StoreResult<Option<Transaction>>;
+
+    async fn get_transactions(&self) -> StoreResult<Vec<Transaction>>;
+
+    /// Cached balance of `address`, zero if it has none.
+    async fn get_balance(&self, address: &Address) -> StoreResult<Amount>;
+
+    async fn get_balances(&self) -> StoreResult<Vec<(Address, Amount)>>;
+
+    async fn update_balance(&self, address: &Address, balance: Amount) -> StoreResult<()>;
+
+    /// Deletes everything derived from the blocks: stored transactions and
+    /// cached balances. Blocks are kept.
+    async fn clear_derived(&self) -> StoreResult<()>;
+
+    /// Stores `transactions`, skipping those that are already stored.
+    async fn index_transactions(&self, transactions: &[Transaction]) -> StoreResult<()>;
+
+    /// Inserts or replaces the record for `record.addr`.
+    async fn save_peer(&self, record: &PeerRecord) -> StoreResult<()>;
+
+    async fn get_peers(&self) -> StoreResult<Vec<PeerRecord>>;
+}
+
+pub type Store = Arc<dyn ChainStore>;
+
+/// Opens the backend named by `SERENITY_STORE`: `mongodb` (the default),
+/// `sqlite` or `memory`. The SQLite file is read from `SERENITY_SQLITE_PATH`.
+pub async fn open_store() -> StoreResult<Store> {
+    let backend = std::env::var("SERENITY_STORE").unwrap_or_else(|_| "mongodb".to_string());
+    let store: Store = match backend.as_str() {
+        "sqlite" => {
+            let path = std::env::var("SERENITY_SQLITE_PATH").unwrap_or_else(|_| "serenity.db".to_string());
+            Arc::new(SqliteStore::open(&path)?)
+        }
+        "memory" => Arc::new(MemoryStore::new()),
+        "mongodb" => Arc::new(MongoDB::new().await),
+        other => return Err(StoreError::Config(format!("unknown SERENITY_STORE {:?}, expected mongodb, sqlite or memory", other))),
+    };
+    info!("Using {} store", backend);
+    Ok(store)
+}
@@ -0,0 +1,147 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+
+use crate::blockchain::amount::Amount;
+use crate::blockchain::block::Block;
+use crate::blockchain::db::core::{ChainStore, StoreError, StoreResult};
+use crate::blockchain::p2p::peers::PeerRecord;
+use crate::blockchain::transaction::{Transaction, TransactionID};
+use crate::blockchain::wallet::Address;
+
+#[derive(Debug, Default)]
+struct MemoryState {
+    blocks: Vec<Block>,
+    transactions: Vec<Transaction>,
+    transaction_ids: HashMap<TransactionID, usize>,
+    balances: HashMap<Address, Amount>,
+    peers: HashMap<SocketAddr, PeerRecord>,
+}
+
+impl MemoryState {
+    /// Stores `transaction` unless one with the same ID is already stored.
+    fn index_transaction(&mut self, transaction: &Transaction) {
+        let position = self.transactions.len();
+        if let Entry::Vacant(entry) = self.transaction_ids.entry(transaction.id()) {
+            entry.insert(position);
+            self.transactions.push(transaction.clone());
+        }
+    }
+}
+
+/// Keeps everything in process memory. Nothing survives a restart, which
+/// makes it handy for tests and throwaway nodes. Clones share the same data.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStore {
+    state: Arc<Mutex<MemoryState>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+}
+
+#[async_trait]
+impl ChainStore for MemoryStore {
+    async fn migrate(&self) -> StoreResult<()> {
+        Ok(())
+    }
+
+    /// Checks for conflicts before touching anything, then applies every
+    /// write under a single lock.
+    async fn commit_block(&self, block: &Block, balances: &[(Address, Amount)]) -> StoreResult<()> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(stored) = state.blocks.iter().find(|stored| stored.index == block.index || stored.hash == block.hash) {
+            return Err(StoreError::Duplicate(format!("block {}", stored.index)));
+        }
+        for transaction in &block.transactions {
+            state.index_transaction(transaction);
+        }
+        state.balances.extend(balances.iter().copied());
+        state.blocks.push(block.clone());
+        state.blocks.sort_by_key(|block| block.index);
+        Ok(())
+    }
+
+    async fn replace_blocks(&self, from: u32, blocks: &[Block], balances: &[(Address, Amount)]) -> StoreResult<()> {
+        let mut state = self.state.lock().unwrap();
+        let kept = state.blocks.iter().filter(|stored| stored.index < from);
+        if let Some(stored) = kept.clone().find(|stored| blocks.iter().any(|block| block.hash == stored.hash)) {
+            return Err(StoreError::Duplicate(format!("block {}", stored.index)));
+        }
+        state.blocks.retain(|stored| stored.index < from);
+        for transaction in blocks.iter().flat_map(|block| &block.transactions) {
+            state.index_transaction(transaction);
+        }
+        state.balances.extend(balances.iter().copied());
+        state.blocks.extend(blocks.iter().cloned());
+        state.blocks.sort_by_key(|block| block.index);
+        Ok(())
+    }
+
+    async fn get_blocks_range(&self, from: u32, to: u32) -> StoreResult<Vec<Block>> {
+        let state = self.state.lock().unwrap();
+        Ok(state.blocks.iter().filter(|block| (from..to).contains(&block.index)).cloned().collect())
+    }
+
+    async fn insert_transaction(&self, transaction: &Transaction) -> StoreResult<()> {
+        let mut state = self.state.lock().unwrap();
+        let id = transaction.id();
+        if state.transaction_ids.contains_key(&id) {
+            return Err(StoreError::Duplicate(format!("transaction {}", id)));
+        }
+        state.index_transaction(transaction);
+        Ok(())
+    }
+
+    async fn get_transaction(&self, id: &TransactionID) -> StoreResult<Option<Transaction>> {
+        let state = self.state.lock().unwrap();
+        Ok(state.transaction_ids.get(id).map(|&position| state.transactions[position].clone()))
+    }
+
+    async fn get_transactions(&self) -> StoreResult<Vec<Transaction>> {
+        Ok(self.state.lock().unwrap().transactions.clone())
+    }
+
+    async fn get_balance(&self, address: &Address) -> StoreResult<Amount> {
+        Ok(self.state.lock().unwrap().balances.get(address).copied().unwrap_or_default())
+    }
+
+    async fn get_balances(&self) -> StoreResult<Vec<(Address, Amount)>> {
+        Ok(self.state.lock().unwrap().balances.iter().map(|(address, balance)| (*address, *balance)).collect())
+    }
+
+    async fn update_balance(&self, address: &Address, balance: Amount) -> StoreResult<()> {
+        self.state.lock().unwrap().balances.insert(*address, balance);
+        Ok(())
+    }
+
+    async fn clear_derived(&self) -> StoreResult<()> {
+        let mut state = self.state.lock().unwrap();
+        state.transactions.clear();
+        state.transaction_ids.clear();
+        state.balances.clear();
+        Ok(())
+    }
+
+    async fn index_transactions(&self, transactions: &[Transaction]) -> StoreResult<()> {
+        let mut state = self.state.lock().unwrap();
+        for transaction in transactions {
+            state.index_transaction(transaction);
+        }
+        Ok(())
+    }
+
+    async fn save_peer(&self, record: &PeerRecord) -> StoreResult<()> {
+        self.state.lock().unwrap().peers.insert(record.addr, record.clone());
+        Ok(())
+    }
+
+    async fn get_peers(&self) -> StoreResult<Vec<PeerRecord>> {
+        Ok(self.state.lock().unwrap().peers.values().cloned().collect())
+    }
+}
@@ -1,14 +1,20 @@
 #![allow(unused)]
+use async_trait::async_trait;
 use futures::TryStreamExt;
 use log::{debug, info};
 use mongodb::{ 
-    bson::{doc, Document},
+    bson::{self, doc, Bson, Document},
+    error::ErrorKind,
     Client, Collection,
 };
 use serde::Serialize;
 use serde_json::to_string;
 
-use crate::blockchain::{block::Block, transaction::Transaction};
+use crate::blockchain::{amount::Amount, block::{Block, BlockID}, transaction::{Transaction, TransactionID}, wallet::Address};
+use crate::blockchain::db::core::{ChainStore, StoreError, StoreResult};
+use crate::blockchain::db::mongodb::migrations;
+use crate::blockchain::merkle::{decode_hash, MerkleHash};
+use crate::blockchain::p2p::peers::PeerRecord;
 
 
 #[derive(Debug, Clone, Serialize)]
@@ -26,95 +32,195 @@ impl MongoDB {
         }
     }
 
-    pub async fn insert_block(&self, block: Block) -> mongodb::error::Result<()> {
-        let collection: Collection<Document> = self.client.database("SERENITY").collection("BLOCKCHAIN");
-        let document = doc! {
-            "index": block.index,
-            "timestamp": block.timestamp as i64,
-            "data": block.data.clone(),
-            "prev_hash": block.prev_hash.clone(),
-            "hash": block.hash.clone(),
-            "nonce": block.nonce as i64,
-            "difficulty": block.difficulty as i64,
-        };
-        let _ = collection.insert_one(document).await;
-        debug!("Block inserted into MongoDB");
+    /// Writes `blocks`, their transactions and `balances` in one session
+    /// transaction, after deleting the blocks from index `from` on if given.
+    async fn write_blocks(&self, from: Option<u32>, new_blocks: &[Block], balances: &[(Address, Amount)]) -> StoreResult<()> {
+        let db = self.client.database("SERENITY");
+        let blocks: Collection<Document> = db.collection("BLOCKCHAIN");
+        let transactions: Collection<Document> = db.collection("TRANSACTIONS");
+        let wallets: Collection<Document> = db.collection("WALLETS");
+        let documents = new_blocks.iter().map(block_to_document).collect::<StoreResult<Vec<_>>>()?;
+
+        let mut session = self.client.start_session().await?;
+        session.start_transaction().await?;
+        let result: StoreResult<()> = async {
+            if let Some(from) = from {
+                blocks.delete_many(doc! { "index": { "$gte": from as i64 } }).session(&mut session).await?;
+            }
+            for document in documents {
+                blocks.insert_one(document).session(&mut session).await?;
+            }
+            for transaction in new_blocks.iter().flat_map(|block| &block.transactions) {
+                let filter = doc! { "_id": transaction.id().as_hex() };
+                let update = doc! { "$setOnInsert": transaction_fields(transaction)? };
+                transactions.update_one(filter, update).upsert(true).session(&mut session).await?;
+            }
+            for (address, balance) in balances {
+                let filter = doc! { "address": address.to_string() };
+                let update = doc! { "$set": { "balance": balance.base_units() as i64 } };
+                wallets.update_one(filter, update).upsert(true).session(&mut session).await?;
+            }
+            Ok(())
+        }
+        .await;
+
+        match result {
+            Ok(()) => session.commit_transaction().await?,
+            Err(err) => {
+                session.abort_transaction().await?;
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ChainStore for MongoDB {
+    /// Runs all writes in one session transaction, which needs MongoDB to
+    /// run as a replica set (a single node replica set is enough).
+    async fn commit_block(&self, block: &Block, balances: &[(Address, Amount)]) -> StoreResult<()> {
+        self.write_blocks(None, std::slice::from_ref(block), balances).await?;
+        debug!("Block {} committed to MongoDB", block.index);
         Ok(())
     }
 
-    pub async fn update_block_transactions(&self, block: &Block, transaction: &Transaction) -> mongodb::error::Result<()> {
-        // add transaction to block
-        let collection: Collection<Document> = self.client.database("SERENITY").collection("BLOCKCHAIN");
-        let filter = doc! { "index": block.index };
-        let update = doc! { "$push": { "transactions": to_string(transaction).unwrap() } };
-        let _ = collection.update_one(filter, update).await?;
+    async fn replace_blocks(&self, from: u32, blocks: &[Block], balances: &[(Address, Amount)]) -> StoreResult<()> {
+        self.write_blocks(Some(from), blocks, balances).await?;
+        debug!("Replaced blocks from {} with {} in MongoDB", from, blocks.len());
         Ok(())
     }
 
-    pub async fn get_balance(&self, address: &str) -> mongodb::error::Result<f64> {
+    async fn get_balance(&self, address: &Address) -> StoreResult<Amount> {
         let collection: Collection<Document> = self.client.database("SERENITY").collection("WALLETS");
-        let filter = doc! { "address": address };
+        let filter = doc! { "address": address.to_string() };
         let document = collection.find_one(filter).await?;
         let document = document.unwrap_or_default();
-        let balance = document.get_f64("balance").unwrap_or_default();
-        Ok(balance)
+        let balance = document.get_i64("balance").unwrap_or_default();
+        Ok(Amount::from_base_units(balance as u64))
+    }
+
+    async fn get_balances(&self) -> StoreResult<Vec<(Address, Amount)>> {
+        let collection: Collection<Document> = self.client.database("SERENITY").collection("WALLETS");
+        let mut cursor = collection.find(doc! {}).await?;
+        let mut balances = vec![];
+
+        while let Some(doc) = cursor.try_next().await? {
+            let Ok(address) = doc.get_str("address").unwrap_or_default().parse() else {
+                debug!("Skipping wallet with invalid address: {:?}", doc);
+                continue;
+            };
+            let balance = doc.get_i64("balance").unwrap_or_default();
+            balances.push((address, Amount::from_base_units(balance as u64)));
+        }
+        Ok(balances)
     }
 
-    pub async fn update_balance(&self, address: &str, balance: f64) -> mongodb::error::Result<()> {
+    async fn update_balance(&self, address: &Address, balance: Amount) -> StoreResult<()> {
         let collection: Collection<Document> = self.client.database("SERENITY").collection("WALLETS");
-        let filter = doc! { "address": address };
-        let update = doc! { "$set": { "balance": balance } };
+        let filter = doc! { "address": address.to_string() };
+        let update = doc! { "$set": { "balance": balance.base_units() as i64 } };
         let options = mongodb::options::UpdateOptions::builder().upsert(true).build();
         let _ = collection.update_one(filter, update).with_options(options).await?;
         Ok(())
     }
 
-    pub async fn insert_transaction(&self, transaction: &Transaction) -> mongodb::error::Result<()> {
+    async fn insert_transaction(&self, transaction: &Transaction) -> StoreResult<()> {
         let collection: Collection<Document> = self.client.database("SERENITY").collection("TRANSACTIONS");
-        let document = doc! {
-            "transaction": to_string(transaction).unwrap(),
-        };
-        let _ = collection.insert_one(document).await?;
+        let id = transaction.id().as_hex();
+        let mut document = doc! { "_id": id.clone() };
+        document.extend(transaction_fields(transaction)?);
+        collection.insert_one(document).await.map_err(|err| match is_duplicate_key(&err) {
+            true => StoreError::Duplicate(format!("transaction {}", id)),
+            false => err.into(),
+        })?;
         Ok(())
     }
 
-    pub async fn get_transactions(&self) -> mongodb::error::Result<Vec<Transaction>> {
+    async fn get_transaction(&self, id: &TransactionID) -> StoreResult<Option<Transaction>> {
+        let collection: Collection<Document> = self.client.database("SERENITY").collection("TRANSACTIONS");
+        let document = collection.find_one(doc! { "_id": id.as_hex() }).await?;
+        document.map(|doc| parse_transaction(&doc)).transpose()
+    }
+
+    async fn get_transactions(&self) -> StoreResult<Vec<Transaction>> {
         let collection: Collection<Document> = self.client.database("SERENITY").collection("TRANSACTIONS");
         let mut cursor = collection.find(doc! {}).await?;
         let mut transactions = vec![];
 
         while let Some(doc) = cursor.try_next().await? {
-            let transaction: Transaction = serde_json::from_str(doc.get_str("transaction").unwrap_or_default()).unwrap();
-            transactions.push(transaction);
+            transactions.push(parse_transaction(&doc)?);
         }
 
         Ok(transactions)
     }
 
-    pub async fn migrate(&self) -> mongodb::error::Result<()> {
+    /// Fails with `StoreError::StandaloneMongo` before touching anything
+    /// if the server can't run the transactions `commit_block` needs.
+    async fn migrate(&self) -> StoreResult<()> {
         let db = self.client.database("SERENITY");
-        let _ = db.create_collection("BLOCKCHAIN").await?;
-        let _ = db.create_collection("TRANSACTIONS").await?;
-        let _ = db.create_collection("WALLETS").await?;
+        let hello = db.run_command(doc! { "hello": 1 }).await?;
+        if !supports_transactions(&hello) {
+            return Err(StoreError::StandaloneMongo);
+        }
+        migrations::run(&db).await
+    }
+
+    async fn clear_derived(&self) -> StoreResult<()> {
+        let db = self.client.database("SERENITY");
+        db.collection::<Document>("TRANSACTIONS").delete_many(doc! {}).await?;
+        db.collection::<Document>("WALLETS").delete_many(doc! {}).await?;
+        Ok(())
+    }
+
+    async fn index_transactions(&self, transactions: &[Transaction]) -> StoreResult<()> {
+        let collection: Collection<Document> = self.client.database("SERENITY").collection("TRANSACTIONS");
+        for transaction in transactions {
+            let filter = doc! { "_id": transaction.id().as_hex() };
+            let update = doc! { "$setOnInsert": transaction_fields(transaction)? };
+            collection.update_one(filter, update).upsert(true).await?;
+        }
+        Ok(())
+    }
+
+    async fn save_peer(&self, record: &PeerRecord) -> StoreResult<()> {
+        let collection: Collection<Document> = self.client.database("SERENITY").collection("PEERS");
+        let filter = doc! { "address": record.addr.to_string() };
+        let update = doc! {
+            "$set": {
+                "last_connected": record.last_connected.map(|time| time as i64),
+                "banned_until": record.banned_until.map(|time| time as i64),
+            }
+        };
+        collection.update_one(filter, update).upsert(true).await?;
         Ok(())
     }
 
-        pub async fn get_blocks(&self) -> mongodb::error::Result<Vec<Block>> {
+    async fn get_peers(&self) -> StoreResult<Vec<PeerRecord>> {
+        let collection: Collection<Document> = self.client.database("SERENITY").collection("PEERS");
+        let documents: Vec<Document> = collection.find(doc! {}).await?.try_collect().await?;
+        documents
+            .iter()
+            .map(|document| {
+                let address = document.get_str("address").unwrap_or_default();
+                let addr = address.parse().map_err(|err| StoreError::Corrupt(format!("peer {:?}: {}", address, err)))?;
+                Ok(PeerRecord {
+                    addr,
+                    last_connected: document.get_i64("last_connected").ok().map(|time| time as u64),
+                    banned_until: document.get_i64("banned_until").ok().map(|time| time as u64),
+                })
+            })
+            .collect()
+    }
+
+    async fn get_blocks_range(&self, from: u32, to: u32) -> StoreResult<Vec<Block>> {
         let collection: Collection<Document> = self.client.database("SERENITY").collection("BLOCKCHAIN");
-        let mut cursor = collection.find(doc! {}).await?;
+        let filter = doc! { "index": { "$gte": from as i64, "$lt": to as i64 } };
+        let mut cursor = collection.find(filter).sort(doc! { "index": 1 }).await?;
         let mut blocks = vec![];
     
         while let Some(doc) = cursor.try_next().await? {
-            let block = Block {
-                index: doc.get_i64("index").unwrap_or_default() as u32,
-                timestamp: doc.get_i64("timestamp").unwrap_or_default() as u64,
-                data: doc.get_str("data").unwrap_or_default().to_string(),
-                prev_hash: doc.get_str("prev_hash").unwrap_or_default().to_string(),
-                hash: doc.get_str("hash").unwrap_or_default().to_string(),
-                nonce: doc.get_i64("nonce").unwrap_or_default() as u64,
-                transactions: vec![],
-                difficulty: doc.get_i64("difficulty").unwrap_or(1) as u32,
-            };
+            let block = document_to_block(&doc)?;
             debug!("Block: {:?}", block);
             blocks.push(block);
         }
@@ -122,14 +228,100 @@ impl MongoDB {
     }
 }
 
+/// Converts a block to its `BLOCKCHAIN` document. Transactions are embedded
+/// as sub-documents in block order so the block can be reloaded in full.
+/// `u64` fields are stored bit-for-bit as `i64`.
+fn block_to_document(block: &Block) -> StoreResult<Document> {
+    let transactions = block
+        .transactions
+        .iter()
+        .map(|transaction| bson::to_document(transaction).map(Bson::Document))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| StoreError::Corrupt(format!("transaction: {}", err)))?;
+    Ok(doc! {
+        "index": block.index as i64,
+        "timestamp": block.timestamp as i64,
+        "data": block.data.clone(),
+        "prev_hash": block.prev_hash.as_hex(),
+        "hash": block.hash.as_hex(),
+        "merkle_root": hex::encode(block.merkle_root),
+        "nonce": block.nonce as i64,
+        "difficulty": block.difficulty as i64,
+        "transactions": transactions,
+    })
+}
+
+pub fn document_to_block(doc: &Document) -> StoreResult<Block> {
+    let transactions = match doc.get_array("transactions") {
+        Ok(transactions) => transactions
+            .iter()
+            .map(|transaction| {
+                bson::from_bson(transaction.clone())
+                    .map_err(|err| StoreError::Corrupt(format!("transaction {}: {}", transaction, err)))
+            })
+            .collect::<StoreResult<Vec<Transaction>>>()?,
+        Err(_) => vec![],
+    };
+    Ok(Block {
+        index: doc.get_i64("index").unwrap_or_default() as u32,
+        timestamp: doc.get_i64("timestamp").unwrap_or_default() as u64,
+        data: doc.get_str("data").unwrap_or_default().to_string(),
+        prev_hash: parse_block_id(doc, "prev_hash")?,
+        hash: parse_block_id(doc, "hash")?,
+        merkle_root: parse_merkle_root(doc)?,
+        nonce: doc.get_i64("nonce").unwrap_or_default() as u64,
+        transactions,
+        difficulty: doc.get_i64("difficulty").unwrap_or(1) as u32,
+    })
+}
+
+/// Reads a hex encoded `BlockID` field, rejecting missing or malformed hashes.
+fn parse_block_id(doc: &Document, key: &str) -> StoreResult<BlockID> {
+    let value = doc.get_str(key).map_err(|err| StoreError::Corrupt(format!("{}: {}", key, err)))?;
+    let id = value.parse().map_err(|err| StoreError::Corrupt(format!("{} {:?}: {}", key, value, err)))?;
+    Ok(id)
+}
+
+/// Reads the hex encoded `merkle_root`, rejecting missing or malformed roots.
+fn parse_merkle_root(doc: &Document) -> StoreResult<MerkleHash> {
+    let value = doc.get_str("merkle_root").map_err(|err| StoreError::Corrupt(format!("merkle_root: {}", err)))?;
+    decode_hash(value).ok_or_else(|| StoreError::Corrupt(format!("merkle_root {:?}", value)))
+}
+
+/// The `TRANSACTIONS` fields besides `_id`. Sender and receiver are copied
+/// out of the transaction body so they can be indexed.
+fn transaction_fields(transaction: &Transaction) -> StoreResult<Document> {
+    let body = bson::to_document(transaction).map_err(|err| StoreError::Corrupt(format!("transaction: {}", err)))?;
+    Ok(doc! {
+        "sender": transaction.sender.to_string(),
+        "receiver": transaction.receiver.to_string(),
+        "transaction": body,
+    })
+}
+
+fn parse_transaction(doc: &Document) -> StoreResult<Transaction> {
+    let body = doc.get_document("transaction").map_err(|err| StoreError::Corrupt(format!("transaction: {}", err)))?;
+    bson::from_document(body.clone()).map_err(|err| StoreError::Corrupt(format!("transaction {}: {}", body, err)))
+}
+
+/// Whether the server that answered `hello` is a replica set member or a
+/// `mongos` router, the deployments with multi-document transactions.
+fn supports_transactions(hello: &Document) -> bool {
+    hello.contains_key("setName") || hello.get_str("msg") == Ok("isdbgrid")
+}
+
+fn is_duplicate_key(err: &mongodb::error::Error) -> bool {
+    matches!(
+        err.kind.as_ref(),
+        ErrorKind::Write(mongodb::error::WriteFailure::WriteError(write_error)) if write_error.code == 11000
+    )
+}
+
 pub async fn connect() -> mongodb::error::Result<Client> {
     // Read MongoDB connection string from environment. Do not hardcode secrets.
     let uri = std::env::var("MONGODB_URI").unwrap_or_else(|_| "mongodb://127.0.0.1:27017".to_string());
 
     let client = Client::with_uri_str(uri).await?;
-    let db = client.database("SERENITY");
-    let collection: Collection<Document> = db.collection("BLOCKCHAIN");
-    let _ = collection.insert_one(doc! { "test": "test" });
 
     info!("Connected to MongoDB");
     Ok(client)
@@ -150,4 +342,33 @@ pub async fn connect() -> mongodb::error::Result<Client> {
 
 //     let _ = collection.insert_one(document);
 //     Ok(())
-// }
\ No newline at end of file
+// }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::SigningKey;
+
+    #[test]
+    fn block_document_round_trip() {
+        let miner = Address::from([3; 20]);
+        let mut transfer = Transaction::new(Address::default(), miner, Amount::from_coins(2), 1_700_000_000, Amount::from_base_units(2_000_000));
+        transfer.sign(&SigningKey::from_bytes(&[7; 32]));
+        let reward = Transaction::reward(miner, Amount::from_coins(50), Amount::ZERO, 1_700_000_000, 1);
+
+        let mut block = Block::new(1, String::new(), BlockID::from([1; 32]));
+        block.set_transactions(vec![transfer, reward]);
+        block.hash = BlockID::from([2; 32]);
+        block.nonce = u64::MAX;
+
+        let document = block_to_document(&block).unwrap();
+        assert!(document.get_array("transactions").unwrap().iter().all(|tx| tx.as_document().is_some()));
+        assert_eq!(document_to_block(&document).unwrap(), block);
+    }
+
+    #[test]
+    fn detects_standalone_servers() {
+        assert!(!supports_transactions(&doc! { "isWritablePrimary": true, "maxWireVersion": 21 }));
+        assert!(supports_transactions(&doc! { "isWritablePrimary": true, "setName": "rs0" }));
+        assert!(supports_transactions(&doc! { "isWritablePrimary": true, "msg": "isdbgrid" }));
+    }
+}
@@ -0,0 +1,281 @@
+use futures::future::BoxFuture;
+use futures::TryStreamExt;
+use log::info;
+use mongodb::{
+    bson::{self, doc, Bson, Document},
+    options::IndexOptions,
+    Collection, Database, IndexModel,
+};
+
+use crate::blockchain::amount::COIN;
+use crate::blockchain::core::Blockchain;
+use crate::blockchain::db::core::{StoreError, StoreResult};
+use crate::blockchain::db::mongodb::core::document_to_block;
+
+/// Schema version written by the newest migration below.
+pub const SCHEMA_VERSION: i64 = 6;
+
+/// One schema upgrade. Every step must be safe to re-run, since a crash
+/// between the step and the version bump runs it again on the next start.
+struct Migration {
+    version: i64,
+    description: &'static str,
+    run: for<'a> fn(&'a Database) -> BoxFuture<'a, StoreResult<()>>,
+}
+
+const MIGRATIONS: [Migration; 6] = [
+    Migration { version: 1, description: "create collections", run: create_collections },
+    Migration { version: 2, description: "store transactions as BSON documents", run: transactions_to_bson },
+    Migration { version: 3, description: "store amounts as integer base units", run: amounts_to_base_units },
+    Migration { version: 4, description: "index blocks, transactions and wallets", run: create_indexes },
+    Migration { version: 5, description: "create the peers collection", run: create_peers },
+    Migration { version: 6, description: "check the stored chain still validates", run: check_chain },
+];
+
+/// Brings the database up to `SCHEMA_VERSION`, running each pending step in
+/// order and recording its version in the `METADATA` collection.
+pub async fn run(db: &Database) -> StoreResult<()> {
+    let metadata: Collection<Document> = db.collection("METADATA");
+    let current = match metadata.find_one(doc! { "_id": "schema" }).await? {
+        Some(document) => document.get_i64("version").unwrap_or_default(),
+        None => 0,
+    };
+    if current > SCHEMA_VERSION {
+        return Err(StoreError::UnsupportedSchema { found: current, supported: SCHEMA_VERSION });
+    }
+
+    for migration in MIGRATIONS.iter().filter(|migration| migration.version > current) {
+        info!("Migrating schema to version {}: {}", migration.version, migration.description);
+        (migration.run)(db).await?;
+        metadata
+            .update_one(doc! { "_id": "schema" }, doc! { "$set": { "version": migration.version } })
+            .upsert(true)
+            .await?;
+    }
+    info!("Schema is at version {}", SCHEMA_VERSION.max(current));
+    Ok(())
+}
+
+fn create_collections(db: &Database) -> BoxFuture<'_, StoreResult<()>> {
+    Box::pin(async move {
+        let existing = db.list_collection_names().await?;
+        for name in ["BLOCKCHAIN", "TRANSACTIONS", "WALLETS"] {
+            if !existing.iter().any(|collection| collection == name) {
+                db.create_collection(name).await?;
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Early versions stored transactions as JSON strings, both in
+/// `TRANSACTIONS` and pushed onto a block's `transactions`.
+fn transactions_to_bson(db: &Database) -> BoxFuture<'_, StoreResult<()>> {
+    Box::pin(async move {
+        rewrite_documents(&db.collection("TRANSACTIONS"), |document| {
+            let mut changed = json_to_document(document, "transaction")?;
+            let transaction = document.get_document("transaction").cloned().unwrap_or_default();
+            for key in ["sender", "receiver"] {
+                if let (false, Ok(address)) = (document.contains_key(key), transaction.get_str(key)) {
+                    document.insert(key, address);
+                    changed = true;
+                }
+            }
+            Ok(changed)
+        })
+        .await?;
+        rewrite_documents(&db.collection("BLOCKCHAIN"), |block| {
+            let Ok(transactions) = block.get_array_mut("transactions") else {
+                return Ok(false);
+            };
+            let mut changed = false;
+            for transaction in transactions.iter_mut() {
+                if let Bson::String(json) = transaction {
+                    *transaction = Bson::Document(parse_json(json)?);
+                    changed = true;
+                }
+            }
+            Ok(changed)
+        })
+        .await
+    })
+}
+
+/// Amounts and balances used to be `f64` coins.
+fn amounts_to_base_units(db: &Database) -> BoxFuture<'_, StoreResult<()>> {
+    Box::pin(async move {
+        rewrite_documents(&db.collection("WALLETS"), |wallet| Ok(coins_to_base_units(wallet, &["balance"])))
+            .await?;
+        rewrite_documents(&db.collection("TRANSACTIONS"), |document| {
+            Ok(document
+                .get_document_mut("transaction")
+                .map(|transaction| coins_to_base_units(transaction, &["amount", "fee"]))
+                .unwrap_or(false))
+        })
+        .await?;
+        rewrite_documents(&db.collection("BLOCKCHAIN"), |block| {
+            let Ok(transactions) = block.get_array_mut("transactions") else {
+                return Ok(false);
+            };
+            let mut changed = false;
+            for transaction in transactions.iter_mut().filter_map(Bson::as_document_mut) {
+                changed |= coins_to_base_units(transaction, &["amount", "fee"]);
+            }
+            Ok(changed)
+        })
+        .await
+    })
+}
+
+fn create_indexes(db: &Database) -> BoxFuture<'_, StoreResult<()>> {
+    Box::pin(async move {
+        let unique = || IndexOptions::builder().unique(true).build();
+        let index = |keys: Document, options: Option<IndexOptions>| IndexModel::builder().keys(keys).options(options).build();
+        let blocks: Collection<Document> = db.collection("BLOCKCHAIN");
+        blocks.create_index(index(doc! { "index": 1 }, Some(unique()))).await?;
+        blocks.create_index(index(doc! { "hash": 1 }, Some(unique()))).await?;
+        let transactions: Collection<Document> = db.collection("TRANSACTIONS");
+        transactions.create_index(index(doc! { "sender": 1 }, None)).await?;
+        transactions.create_index(index(doc! { "receiver": 1 }, None)).await?;
+        let wallets: Collection<Document> = db.collection("WALLETS");
+        wallets.create_index(index(doc! { "address": 1 }, Some(unique()))).await?;
+        Ok(())
+    })
+}
+
+fn create_peers(db: &Database) -> BoxFuture<'_, StoreResult<()>> {
+    Box::pin(async move {
+        if !db.list_collection_names().await?.iter().any(|collection| collection == "PEERS") {
+            db.create_collection("PEERS").await?;
+        }
+        let peers: Collection<Document> = db.collection("PEERS");
+        let options = IndexOptions::builder().unique(true).build();
+        peers.create_index(IndexModel::builder().keys(doc! { "address": 1 }).options(options).build()).await?;
+        Ok(())
+    })
+}
+
+/// Blocks of early versions were hashed differently and don't follow the
+/// difficulty rules, and rewriting them would change every hash. There is
+/// no upgrade for such a chain, so this fails with what to do about it
+/// rather than leaving the node to reject the chain on every start.
+fn check_chain(db: &Database) -> BoxFuture<'_, StoreResult<()>> {
+    Box::pin(async move {
+        let blocks: Collection<Document> = db.collection("BLOCKCHAIN");
+        let documents: Vec<Document> = blocks.find(doc! {}).sort(doc! { "index": 1 }).await?.try_collect().await?;
+        validate_documents(&documents)
+    })
+}
+
+fn validate_documents(documents: &[Document]) -> StoreResult<()> {
+    if documents.is_empty() {
+        return Ok(());
+    }
+    let chain = documents
+        .iter()
+        .map(document_to_block)
+        .collect::<StoreResult<Vec<_>>>()
+        .map_err(|err| StoreError::IncompatibleChain(err.to_string()))?;
+    Blockchain::validate_blocks(&chain).map_err(|err| StoreError::IncompatibleChain(err.to_string()))?;
+    Ok(())
+}
+
+/// Applies `rewrite` to every document in `collection` and replaces the
+/// documents it reports as changed.
+async fn rewrite_documents<F>(collection: &Collection<Document>, mut rewrite: F) -> StoreResult<()>
+where
+    F: FnMut(&mut Document) -> StoreResult<bool>,
+{
+    let documents: Vec<Document> = collection.find(doc! {}).await?.try_collect().await?;
+    let mut rewritten = 0;
+    for mut document in documents {
+        if !rewrite(&mut document)? {
+            continue;
+        }
+        let id = document.get("_id").cloned().unwrap_or(Bson::Null);
+        collection.replace_one(doc! { "_id": id }, document).await?;
+        rewritten += 1;
+    }
+    info!("Rewrote {} documents in {}", rewritten, collection.name());
+    Ok(())
+}
+
+/// Replaces a JSON string field with the equivalent sub-document.
+fn json_to_document(document: &mut Document, key: &str) -> StoreResult<bool> {
+    let Ok(json) = document.get_str(key) else {
+        return Ok(false);
+    };
+    let parsed = parse_json(json)?;
+    document.insert(key, parsed);
+    Ok(true)
+}
+
+fn parse_json(json: &str) -> StoreResult<Document> {
+    let value: serde_json::Value =
+        serde_json::from_str(json).map_err(|err| StoreError::Corrupt(format!("{:?}: {}", json, err)))?;
+    match bson::to_bson(&value) {
+        Ok(Bson::Document(document)) => Ok(document),
+        _ => Err(StoreError::Corrupt(format!("{:?} is not a JSON object", json))),
+    }
+}
+
+/// Converts `f64` coin values under `keys` to integer base units.
+fn coins_to_base_units(document: &mut Document, keys: &[&str]) -> bool {
+    let mut changed = false;
+    for key in keys {
+        if let Some(Bson::Double(coins)) = document.get(*key) {
+            let units = (coins * COIN as f64).round() as i64;
+            document.insert(*key, units);
+            changed = true;
+        }
+    }
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::block::{Block, BlockID};
+    use crate::blockchain::hashing::Hashing;
+
+    #[test]
+    fn upgrades_legacy_transaction_documents() {
+        let json = r#"{"sender":"SaVQRri1UUeBkKQJPRkAM5MLsgSDDibWbd","receiver":"SaVQRri1UUeBkKQJPRkAM5MLsgSDDibWbd","amount":1.5,"timestamp":1700000000,"fee":0.015}"#;
+        let mut document = doc! { "_id": "legacy", "transaction": json };
+
+        assert!(json_to_document(&mut document, "transaction").unwrap());
+        assert!(!json_to_document(&mut document, "transaction").unwrap());
+        let transaction = document.get_document_mut("transaction").unwrap();
+        assert!(coins_to_base_units(transaction, &["amount", "fee"]));
+        assert!(!coins_to_base_units(transaction, &["amount", "fee"]));
+        assert_eq!(transaction.get_i64("amount"), Ok(150_000_000));
+        assert_eq!(transaction.get_i64("fee"), Ok(1_500_000));
+        assert!(json_to_document(&mut doc! { "transaction": "not json" }, "transaction").is_err());
+    }
+
+    #[test]
+    fn rejects_chains_that_no_longer_validate() {
+        let mut genesis = Block::new(0, "Genesis Block".to_string(), BlockID::default());
+        genesis.hash = Hashing::new(genesis.clone()).calculate_hash();
+        let document = |block: &Block| {
+            doc! {
+                "index": block.index as i64,
+                "timestamp": block.timestamp as i64,
+                "data": block.data.clone(),
+                "prev_hash": block.prev_hash.as_hex(),
+                "hash": block.hash.as_hex(),
+                "merkle_root": hex::encode(block.merkle_root),
+                "nonce": block.nonce as i64,
+                "difficulty": block.difficulty as i64,
+                "transactions": [],
+            }
+        };
+        assert!(validate_documents(&[]).is_ok());
+        assert!(validate_documents(&[document(&genesis)]).is_ok());
+
+        // A block hashed the way early versions did.
+        let mut legacy = genesis.clone();
+        legacy.hash = BlockID::from([7; 32]);
+        assert!(matches!(validate_documents(&[document(&legacy)]), Err(StoreError::IncompatibleChain(_))));
+    }
+}
@@ -0,0 +1,408 @@
+use async_trait::async_trait;
+use log::debug;
+use r2d2::Pool;
+use r2d2_sqlite::rusqlite::{params, Connection, ErrorCode, OptionalExtension, Row};
+use r2d2_sqlite::SqliteConnectionManager;
+
+use crate::blockchain::amount::Amount;
+use crate::blockchain::block::Block;
+use crate::blockchain::db::core::{ChainStore, StoreError, StoreResult};
+use crate::blockchain::db::sqlite::tables;
+use crate::blockchain::merkle::decode_hash;
+use crate::blockchain::p2p::peers::PeerRecord;
+use crate::blockchain::transaction::{Transaction, TransactionID};
+use crate::blockchain::wallet::Address;
+
+/// SQLite backed store. Queries run on the blocking thread pool so they
+/// don't stall the async runtime.
+#[derive(Debug, Clone)]
+pub struct SqliteStore {
+    pool: Pool<SqliteConnectionManager>,
+}
+
+impl SqliteStore {
+    /// Opens (or creates) the database file at `path` and its tables.
+    pub fn open(path: &str) -> StoreResult<SqliteStore> {
+        Self::with_manager(SqliteConnectionManager::file(path), 8)
+    }
+
+    /// A private in-memory database. Uses a single connection since every
+    /// SQLite in-memory connection is a separate database.
+    #[allow(dead_code)]
+    pub fn open_in_memory() -> StoreResult<SqliteStore> {
+        Self::with_manager(SqliteConnectionManager::memory(), 1)
+    }
+
+    fn with_manager(manager: SqliteConnectionManager, max_size: u32) -> StoreResult<SqliteStore> {
+        let pool = Pool::builder().max_size(max_size).build(manager)?;
+        tables::create_tables(&*pool.get()?)?;
+        Ok(SqliteStore { pool })
+    }
+
+    async fn with_conn<T, F>(&self, query: F) -> StoreResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> StoreResult<T> + Send + 'static,
+    {
+        let pool = self.pool.clone();
+        tokio::task::spawn_blocking(move || query(&*pool.get()?))
+            .await
+            .expect("SQLite query panicked")
+    }
+}
+
+#[async_trait]
+impl ChainStore for SqliteStore {
+    async fn migrate(&self) -> StoreResult<()> {
+        self.with_conn(|conn| Ok(tables::create_tables(conn)?)).await
+    }
+
+    async fn commit_block(&self, block: &Block, balances: &[(Address, Amount)]) -> StoreResult<()> {
+        let block = block.clone();
+        let balances = balances.to_vec();
+        self.with_conn(move |conn| {
+            let tx = conn.unchecked_transaction()?;
+            insert_block(&tx, &block)?;
+            write_balances(&tx, &balances)?;
+            tx.commit()?;
+            debug!("Block {} committed to SQLite", block.index);
+            Ok(())
+        })
+        .await
+    }
+
+    async fn replace_blocks(&self, from: u32, blocks: &[Block], balances: &[(Address, Amount)]) -> StoreResult<()> {
+        let blocks = blocks.to_vec();
+        let balances = balances.to_vec();
+        self.with_conn(move |conn| {
+            let tx = conn.unchecked_transaction()?;
+            let removed = tx.execute("DELETE FROM blocks WHERE \"index\" >= ?1", params![from])?;
+            for block in &blocks {
+                insert_block(&tx, block)?;
+            }
+            write_balances(&tx, &balances)?;
+            tx.commit()?;
+            debug!("Replaced {} blocks from {} with {} in SQLite", removed, from, blocks.len());
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_blocks_range(&self, from: u32, to: u32) -> StoreResult<Vec<Block>> {
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT \"index\", timestamp, data, prev_hash, hash, merkle_root, nonce, difficulty, transactions
+                 FROM blocks WHERE \"index\" >= ?1 AND \"index\" < ?2 ORDER BY \"index\"",
+            )?;
+            let rows = stmt.query_map(params![from, to], BlockRow::read)?;
+            rows.map(|row| row?.into_block()).collect()
+        })
+        .await
+    }
+
+    async fn insert_transaction(&self, transaction: &Transaction) -> StoreResult<()> {
+        let transaction = transaction.clone();
+        self.with_conn(move |conn| match insert_transaction(conn, "INSERT", &transaction) {
+            Err(err) if err.sqlite_error_code() == Some(ErrorCode::ConstraintViolation) => {
+                Err(StoreError::Duplicate(format!("transaction {}", transaction.id())))
+            }
+            result => Ok(result?),
+        })
+        .await
+    }
+
+    async fn get_transaction(&self, id: &TransactionID) -> StoreResult<Option<Transaction>> {
+        let id = id.as_hex();
+        self.with_conn(move |conn| {
+            let body: Option<String> = conn
+                .query_row("SELECT body FROM transactions WHERE id = ?1", params![id], |row| row.get(0))
+                .optional()?;
+            body.map(|body| parse_json(&body)).transpose()
+        })
+        .await
+    }
+
+    async fn get_transactions(&self) -> StoreResult<Vec<Transaction>> {
+        self.with_conn(|conn| {
+            let mut stmt = conn.prepare("SELECT body FROM transactions")?;
+            let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+            rows.map(|body| parse_json(&body?)).collect()
+        })
+        .await
+    }
+
+    async fn get_balance(&self, address: &Address) -> StoreResult<Amount> {
+        let address = address.to_string();
+        self.with_conn(move |conn| {
+            let balance: Option<i64> = conn
+                .query_row("SELECT balance FROM wallets WHERE address = ?1", params![address], |row| row.get(0))
+                .optional()?;
+            Ok(Amount::from_base_units(balance.unwrap_or_default() as u64))
+        })
+        .await
+    }
+
+    async fn get_balances(&self) -> StoreResult<Vec<(Address, Amount)>> {
+        self.with_conn(|conn| {
+            let mut stmt = conn.prepare("SELECT address, balance FROM wallets")?;
+            let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?)))?;
+            let mut balances = vec![];
+            for row in rows {
+                let (address, balance) = row?;
+                let Ok(address) = address.parse() else {
+                    debug!("Skipping wallet with invalid address: {:?}", address);
+                    continue;
+                };
+                balances.push((address, Amount::from_base_units(balance as u64)));
+            }
+            Ok(balances)
+        })
+        .await
+    }
+
+    async fn clear_derived(&self) -> StoreResult<()> {
+        self.with_conn(|conn| {
+            conn.execute_batch("BEGIN; DELETE FROM transactions; DELETE FROM wallets; COMMIT;")?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn index_transactions(&self, transactions: &[Transaction]) -> StoreResult<()> {
+        let transactions = transactions.to_vec();
+        self.with_conn(move |conn| {
+            let tx = conn.unchecked_transaction()?;
+            for transaction in &transactions {
+                insert_transaction(&tx, "INSERT OR IGNORE", transaction)?;
+            }
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn save_peer(&self, record: &PeerRecord) -> StoreResult<()> {
+        let record = record.clone();
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO peers (address, last_connected, banned_until) VALUES (?1, ?2, ?3)",
+                params![
+                    record.addr.to_string(),
+                    record.last_connected.map(|time| time as i64),
+                    record.banned_until.map(|time| time as i64),
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_peers(&self) -> StoreResult<Vec<PeerRecord>> {
+        self.with_conn(|conn| {
+            let mut stmt = conn.prepare("SELECT address, last_connected, banned_until FROM peers")?;
+            let rows = stmt.query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, Option<i64>>(1)?, row.get::<_, Option<i64>>(2)?))
+            })?;
+            rows.map(|row| {
+                let (address, last_connected, banned_until) = row?;
+                let addr = address.parse().map_err(|err| StoreError::Corrupt(format!("peer {:?}: {}", address, err)))?;
+                Ok(PeerRecord {
+                    addr,
+                    last_connected: last_connected.map(|time| time as u64),
+                    banned_until: banned_until.map(|time| time as u64),
+                })
+            })
+            .collect()
+        })
+        .await
+    }
+
+    async fn update_balance(&self, address: &Address, balance: Amount) -> StoreResult<()> {
+        let address = address.to_string();
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO wallets (address, balance) VALUES (?1, ?2)",
+                params![address, balance.base_units() as i64],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+}
+
+/// Inserts `block` and indexes its transactions.
+fn insert_block(conn: &Connection, block: &Block) -> r2d2_sqlite::rusqlite::Result<()> {
+    conn.execute(
+        "INSERT INTO blocks (\"index\", timestamp, data, prev_hash, hash, merkle_root, nonce, difficulty, transactions)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        params![
+            block.index,
+            block.timestamp as i64,
+            block.data,
+            block.prev_hash.as_hex(),
+            block.hash.as_hex(),
+            hex::encode(block.merkle_root),
+            block.nonce as i64,
+            block.difficulty,
+            serde_json::to_string(&block.transactions).unwrap(),
+        ],
+    )?;
+    for transaction in &block.transactions {
+        insert_transaction(conn, "INSERT OR IGNORE", transaction)?;
+    }
+    Ok(())
+}
+
+fn write_balances(conn: &Connection, balances: &[(Address, Amount)]) -> r2d2_sqlite::rusqlite::Result<()> {
+    for (address, balance) in balances {
+        conn.execute(
+            "INSERT OR REPLACE INTO wallets (address, balance) VALUES (?1, ?2)",
+            params![address.to_string(), balance.base_units() as i64],
+        )?;
+    }
+    Ok(())
+}
+
+/// Runs `verb` (`INSERT` or `INSERT OR IGNORE`) for one `transactions` row.
+fn insert_transaction(conn: &Connection, verb: &str, transaction: &Transaction) -> r2d2_sqlite::rusqlite::Result<()> {
+    conn.execute(
+        &format!("{} INTO transactions (id, sender, receiver, body) VALUES (?1, ?2, ?3, ?4)", verb),
+        params![
+            transaction.id().as_hex(),
+            transaction.sender.to_string(),
+            transaction.receiver.to_string(),
+            serde_json::to_string(transaction).unwrap(),
+        ],
+    )?;
+    Ok(())
+}
+
+/// Raw column values of a `blocks` row.
+struct BlockRow {
+    index: u32,
+    timestamp: i64,
+    data: String,
+    prev_hash: String,
+    hash: String,
+    merkle_root: String,
+    nonce: i64,
+    difficulty: u32,
+    transactions: String,
+}
+
+impl BlockRow {
+    fn read(row: &Row) -> r2d2_sqlite::rusqlite::Result<BlockRow> {
+        Ok(BlockRow {
+            index: row.get(0)?,
+            timestamp: row.get(1)?,
+            data: row.get(2)?,
+            prev_hash: row.get(3)?,
+            hash: row.get(4)?,
+            merkle_root: row.get(5)?,
+            nonce: row.get(6)?,
+            difficulty: row.get(7)?,
+            transactions: row.get(8)?,
+        })
+    }
+
+    fn into_block(self) -> StoreResult<Block> {
+        let parse_id = |key: &str, value: &str| {
+            value.parse().map_err(|err| StoreError::Corrupt(format!("{} {:?}: {}", key, value, err)))
+        };
+        Ok(Block {
+            index: self.index,
+            timestamp: self.timestamp as u64,
+            data: self.data,
+            prev_hash: parse_id("prev_hash", &self.prev_hash)?,
+            hash: parse_id("hash", &self.hash)?,
+            merkle_root: decode_hash(&self.merkle_root)
+                .ok_or_else(|| StoreError::Corrupt(format!("merkle_root {:?}", self.merkle_root)))?,
+            nonce: self.nonce as u64,
+            transactions: parse_json(&self.transactions)?,
+            difficulty: self.difficulty,
+        })
+    }
+}
+
+fn parse_json<T: serde::de::DeserializeOwned>(json: &str) -> StoreResult<T> {
+    serde_json::from_str(json).map_err(|err| StoreError::Corrupt(format!("{:?}: {}", json, err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::block::BlockID;
+
+    #[tokio::test]
+    async fn round_trips_blocks_transactions_and_balances() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let miner = Address::from([3; 20]);
+        let reward = Transaction::reward(miner, Amount::from_coins(50), Amount::ZERO, 1_700_000_000, 1);
+
+        let mut block = Block::new(1, String::new(), BlockID::from([1; 32]));
+        block.set_transactions(vec![reward.clone()]);
+        block.nonce = u64::MAX;
+        store.commit_block(&block, &[]).await.unwrap();
+        assert_eq!(store.get_blocks().await.unwrap(), vec![block]);
+
+        assert!(matches!(store.insert_transaction(&reward).await, Err(StoreError::Duplicate(_))));
+        assert_eq!(store.get_transaction(&reward.id()).await.unwrap(), Some(reward.clone()));
+        assert_eq!(store.get_transactions().await.unwrap(), vec![reward]);
+
+        store.update_balance(&miner, Amount::from_coins(50)).await.unwrap();
+        store.update_balance(&miner, Amount::from_coins(40)).await.unwrap();
+        assert_eq!(store.get_balance(&miner).await.unwrap(), Amount::from_coins(40));
+        assert_eq!(store.get_balances().await.unwrap(), vec![(miner, Amount::from_coins(40))]);
+
+        let mut peer = PeerRecord { addr: "10.0.0.1:9000".parse().unwrap(), last_connected: Some(1), banned_until: None };
+        store.save_peer(&peer).await.unwrap();
+        peer.banned_until = Some(2);
+        store.save_peer(&peer).await.unwrap();
+        assert_eq!(store.get_peers().await.unwrap(), vec![peer]);
+    }
+
+    #[tokio::test]
+    async fn failed_commit_writes_nothing() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let miner = Address::from([3; 20]);
+        let mut block = Block::new(1, String::new(), BlockID::default());
+        block.set_transactions(vec![Transaction::reward(miner, Amount::from_coins(50), Amount::ZERO, 0, 1)]);
+        store.commit_block(&block, &[(miner, Amount::from_coins(50))]).await.unwrap();
+
+        let mut competing = Block::new(1, String::new(), BlockID::default());
+        let other_reward = Transaction::reward(miner, Amount::from_coins(25), Amount::ZERO, 0, 1);
+        competing.set_transactions(vec![other_reward.clone()]);
+        assert!(store.commit_block(&competing, &[(miner, Amount::from_coins(25))]).await.is_err());
+
+        assert_eq!(store.get_blocks().await.unwrap(), vec![block]);
+        assert_eq!(store.get_balance(&miner).await.unwrap(), Amount::from_coins(50));
+        assert_eq!(store.get_transaction(&other_reward.id()).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn returns_block_ranges_in_order() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let blocks: Vec<Block> = (0..5u8)
+            .map(|index| {
+                let mut block = Block::new(index as u32, String::new(), BlockID::from([index; 32]));
+                block.hash = BlockID::from([index + 1; 32]);
+                block
+            })
+            .collect();
+        for block in blocks.iter().rev() {
+            store.commit_block(block, &[]).await.unwrap();
+        }
+
+        assert_eq!(store.get_blocks().await.unwrap(), blocks);
+        assert_eq!(store.get_blocks_range(1, 3).await.unwrap(), blocks[1..3]);
+        assert_eq!(store.get_blocks_range(4, 10).await.unwrap(), blocks[4..]);
+        assert!(store.get_blocks_range(3, 3).await.unwrap().is_empty());
+
+        let mut branch = blocks[3].clone();
+        branch.hash = BlockID::from([9; 32]);
+        let address = Address::from([1; 20]);
+        store.replace_blocks(3, std::slice::from_ref(&branch), &[(address, Amount::from_coins(2))]).await.unwrap();
+        assert_eq!(store.get_blocks().await.unwrap(), [&blocks[..3], &[branch]].concat());
+        assert_eq!(store.get_balance(&address).await.unwrap(), Amount::from_coins(2));
+    }
+}
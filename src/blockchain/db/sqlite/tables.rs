@@ -0,0 +1,70 @@
+use r2d2_sqlite::rusqlite::{Connection, Result};
+
+/// Creates every table the SQLite store uses, keeping existing ones.
+pub fn create_tables(conn: &Connection) -> Result<()> {
+    create_blocks_table(conn)?;
+    create_transaction_table(conn)?;
+    create_wallet_table(conn)?;
+    create_peer_table(conn)?;
+    Ok(())
+}
+
+pub fn create_wallet_table(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS wallets (
+            address TEXT PRIMARY KEY,
+            balance INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Known peer addresses and bans, times in Unix seconds.
+pub fn create_peer_table(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS peers (
+            address TEXT PRIMARY KEY,
+            last_connected INTEGER,
+            banned_until INTEGER
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+/// `transactions` holds the block's transactions as a JSON array.
+pub fn create_blocks_table(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS blocks (
+            \"index\" INTEGER PRIMARY KEY,
+            timestamp INTEGER NOT NULL,
+            data TEXT NOT NULL,
+            prev_hash TEXT NOT NULL,
+            hash TEXT NOT NULL,
+            merkle_root TEXT NOT NULL,
+            nonce INTEGER NOT NULL,
+            difficulty INTEGER NOT NULL,
+            transactions TEXT NOT NULL
+        )",
+        [],
+    )?;
+    conn.execute("CREATE UNIQUE INDEX IF NOT EXISTS blocks_hash ON blocks (hash)", [])?;
+    Ok(())
+}
+
+/// Transactions keyed by their hex ID, stored as JSON.
+pub fn create_transaction_table(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS transactions (
+            id TEXT PRIMARY KEY,
+            sender TEXT NOT NULL,
+            receiver TEXT NOT NULL,
+            body TEXT NOT NULL
+        )",
+        [],
+    )?;
+    conn.execute("CREATE INDEX IF NOT EXISTS transactions_sender ON transactions (sender)", [])?;
+    conn.execute("CREATE INDEX IF NOT EXISTS transactions_receiver ON transactions (receiver)", [])?;
+    Ok(())
+}
@@ -1,87 +1,367 @@
-use log::{error, info};
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use serde_with::{hex::Hex, serde_as};
 use sha2::{Sha256, Digest};
-use crate::blockchain::block::Block;
-use std::sync::atomic::{AtomicU64, Ordering};
+use crate::blockchain::block::{Block, BlockID, BLOCK_ID_LENGTH};
+use crate::blockchain::merkle::MerkleHash;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::Instant;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// The part of a block that is hashed for proof of work.
+///
+/// Canonical encoding, all integers big-endian:
+///
+/// | field           | size                    |
+/// |-----------------|-------------------------|
+/// | `index`         | 4 bytes                 |
+/// | `timestamp`     | 8 bytes                 |
+/// | `difficulty`    | 4 bytes                 |
+/// | `prev_hash`     | 32 bytes                |
+/// | `merkle_root`   | 32 bytes                |
+/// | `nonce`         | 8 bytes                 |
+///
+/// The block hash is the SHA-256 of this encoding. `nonce` comes
+/// last so miners can hash the prefix once and only feed the nonce per attempt.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BlockHeader {
+    pub index: u32,
+    pub timestamp: u64,
+    pub difficulty: u32,
+    pub prev_hash: BlockID,
+    #[serde_as(as = "Hex")]
+    pub merkle_root: MerkleHash,
+    pub nonce: u64,
+}
+
+impl BlockHeader {
+    pub fn from_block(block: &Block) -> BlockHeader {
+        BlockHeader {
+            index: block.index,
+            timestamp: block.timestamp,
+            difficulty: block.difficulty,
+            prev_hash: block.prev_hash,
+            merkle_root: block.merkle_root,
+            nonce: block.nonce,
+        }
+    }
+
+    /// Everything except the trailing nonce.
+    pub fn encode_prefix(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(4 + 8 + 4 + 32 + 32 + 8);
+        bytes.extend_from_slice(&self.index.to_be_bytes());
+        bytes.extend_from_slice(&self.timestamp.to_be_bytes());
+        bytes.extend_from_slice(&self.difficulty.to_be_bytes());
+        bytes.extend_from_slice(self.prev_hash.as_ref());
+        bytes.extend_from_slice(&self.merkle_root);
+        bytes
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = self.encode_prefix();
+        bytes.extend_from_slice(&self.nonce.to_be_bytes());
+        bytes
+    }
+
+    pub fn hash(&self) -> BlockID {
+        BlockID::from(<[u8; BLOCK_ID_LENGTH]>::from(Sha256::digest(self.encode())))
+    }
+}
+
+/// Stops a running `Hashing::mine_block` search. Clones share the flag.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum MiningError {
+    #[error("mining was cancelled")]
+    Cancelled,
+    #[error("no solution found within {0:?}")]
+    TimedOut(Duration),
+}
+
+/// A header that meets its difficulty, with its hash and how many nonces
+/// were tried to find it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MinedHeader {
+    pub header: BlockHeader,
+    pub hash: BlockID,
+    pub attempts: u64,
+}
+
+/// How a single pass over the nonce range ended.
+enum Search {
+    Found(u64, BlockID),
+    Exhausted,
+    Cancelled,
+    TimedOut,
+}
+
+/// Workers look at the clock once per this many nonces.
+const DEADLINE_CHECK_INTERVAL: u64 = 4096;
 
 pub struct Hashing {
     pub block: Block,
+    nonce_range: Range<u64>,
 }
 
 impl Hashing {
     pub fn new(block: Block) -> Hashing {
-        Hashing { block }
+        Hashing { block, nonce_range: 0..u64::MAX }
     }
 
-    pub fn calculate_hash(&self) -> String {
-        let data = format!(
-            "{}{}{}{}{}",
-            self.block.index, self.block.timestamp, self.block.data, self.block.prev_hash, self.block.nonce
-        );
-        let mut hasher = Sha256::new();
-        hasher.update(data.as_bytes());
-        let result = hasher.finalize();
-        hex::encode(result)
+    /// Restricts the search to `nonce_range`, e.g. to split the work
+    /// between miners.
+    #[allow(dead_code)]
+    pub fn with_nonce_range(mut self, nonce_range: Range<u64>) -> Hashing {
+        self.nonce_range = nonce_range;
+        self
+    }
+
+    pub fn calculate_hash(&self) -> BlockID {
+        BlockHeader::from_block(&self.block).hash()
     }
 
-    pub fn mine_block(&mut self, difficulty: u32) {
+    /// Returns true if `hash` is at or below the target for `difficulty`.
+    pub fn meets_difficulty(hash: &BlockID, difficulty: u32) -> bool {
+        u64::from_be_bytes(hash.as_ref()[0..8].try_into().unwrap()) <= Self::target(difficulty)
+    }
+
+    /// Hashes whose first 8 bytes, read big-endian, are at or below this
+    /// meet `difficulty`.
+    pub fn target(difficulty: u32) -> u64 {
+        u64::MAX.checked_shr(difficulty).unwrap_or(0)
+    }
+
+    /// Searches the nonce range on every rayon thread for a hash that meets
+    /// `difficulty`, and stores the solution in the block. Once the whole
+    /// range has been tried the block timestamp moves forward, which gives
+    /// a fresh header to search. Stops early when `cancel` is cancelled or
+    /// `timeout` runs out.
+    pub fn mine_block(&mut self, difficulty: u32, cancel: &CancelToken, timeout: Option<Duration>) -> Result<MinedHeader, MiningError> {
         let now = Instant::now();
-        let target = (1u64 << (64 - difficulty)) - 1;
+        let deadline = timeout.map(|timeout| now + timeout);
         info!("Starting to mine block with difficulty: {}", difficulty);
-    
-        let nonce = Arc::new(AtomicU64::new(0));
-        let found = Arc::new(AtomicU64::new(0));
-    
-        let mut hasher = Sha256::new();
-        hasher.update(&self.block.index.to_le_bytes());
-        hasher.update(&self.block.timestamp.to_le_bytes());
-        hasher.update(&self.block.prev_hash);
-        hasher.update(&self.block.data);
-        let constant_hash = hasher.finalize_reset();
-    
-        let result = Arc::new(Mutex::new(None));
-    
+        self.block.difficulty = difficulty;
+        let attempts = AtomicU64::new(0);
+
+        loop {
+            let header = BlockHeader::from_block(&self.block);
+            match self.search(&header, difficulty, cancel, deadline, &attempts) {
+                Search::Found(nonce, hash) => {
+                    self.block.nonce = nonce;
+                    self.block.hash = hash;
+                    info!("Mining took: {}s", now.elapsed().as_secs_f64());
+                    info!("Block mined: nonce = {}, hash = {}", self.block.nonce, self.block.hash);
+                    return Ok(MinedHeader {
+                        header: BlockHeader { nonce, ..header },
+                        hash,
+                        attempts: attempts.load(Ordering::Relaxed),
+                    });
+                }
+                Search::Exhausted => {
+                    self.block.timestamp = (chrono::Utc::now().timestamp() as u64).max(self.block.timestamp + 1);
+                    debug!("Nonce range exhausted, moved the timestamp to {}", self.block.timestamp);
+                }
+                Search::Cancelled => {
+                    info!("Mining cancelled after {}s", now.elapsed().as_secs_f64());
+                    return Err(MiningError::Cancelled);
+                }
+                Search::TimedOut => {
+                    info!("Mining timed out after {}s", now.elapsed().as_secs_f64());
+                    return Err(MiningError::TimedOut(timeout.unwrap_or_default()));
+                }
+            }
+        }
+    }
+
+    /// One pass over `nonce_range` for `header`.
+    fn search(
+        &self,
+        header: &BlockHeader,
+        difficulty: u32,
+        cancel: &CancelToken,
+        deadline: Option<Instant>,
+        attempts: &AtomicU64,
+    ) -> Search {
+        let target = Self::target(difficulty);
+        let next_nonce = AtomicU64::new(self.nonce_range.start);
+        let stop = AtomicBool::new(false);
+        let outcome = Mutex::new(Search::Exhausted);
+
+        let mut prefix_hasher = Sha256::new();
+        prefix_hasher.update(header.encode_prefix());
+
         rayon::scope(|s| {
             for _ in 0..rayon::current_num_threads() {
-                let nonce = Arc::clone(&nonce);
-                let found = Arc::clone(&found);
-                let constant_hash = constant_hash.clone();
-                let result = Arc::clone(&result);
-    
+                let prefix_hasher = prefix_hasher.clone();
+                let (next_nonce, stop, outcome) = (&next_nonce, &stop, &outcome);
                 s.spawn(move |_| {
-                    let mut hasher = Sha256::new();
-                    let mut local_nonce = nonce.fetch_add(1, Ordering::Relaxed);
-    
-                    while found.load(Ordering::Relaxed) == 0 {
-                        hasher.update(&constant_hash);
-                        hasher.update(&local_nonce.to_le_bytes());
-                        let hash_result = hasher.finalize_reset();
-    
+                    let mut tried = 0;
+                    let finish = |search: Search| {
+                        if !stop.swap(true, Ordering::Relaxed) {
+                            *outcome.lock().unwrap() = search;
+                        }
+                    };
+                    while !stop.load(Ordering::Relaxed) {
+                        if cancel.is_cancelled() {
+                            finish(Search::Cancelled);
+                            break;
+                        }
+                        if tried % DEADLINE_CHECK_INTERVAL == 0 && deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                            finish(Search::TimedOut);
+                            break;
+                        }
+                        let nonce = next_nonce.fetch_add(1, Ordering::Relaxed);
+                        if nonce >= self.nonce_range.end {
+                            finish(Search::Exhausted);
+                            break;
+                        }
+                        tried += 1;
+
+                        let mut hasher = prefix_hasher.clone();
+                        hasher.update(nonce.to_be_bytes());
+                        let hash_result = hasher.finalize();
                         let hash_prefix = u64::from_be_bytes(hash_result[0..8].try_into().unwrap());
                         if hash_prefix <= target {
-                            let mut result_guard = result.lock().unwrap();
-                            *result_guard = Some((local_nonce, hex::encode(hash_result)));
-                            found.store(1, Ordering::Relaxed);
+                            finish(Search::Found(nonce, BlockID::from(<[u8; BLOCK_ID_LENGTH]>::from(hash_result))));
                             break;
                         }
-    
-                        local_nonce = nonce.fetch_add(1, Ordering::Relaxed);
                     }
+                    attempts.fetch_add(tried, Ordering::Relaxed);
                 });
             }
         });
-    
-        let elapsed = now.elapsed();
-        info!("Mining took: {}s", elapsed.as_secs_f64());
-    
-        let result = result.lock().unwrap();
-        if let Some((nonce, hash)) = &*result {
-            self.block.nonce = *nonce;
-            self.block.hash = hash.clone();
-            info!("Block mined: nonce = {}, hash = {}", self.block.nonce, self.block.hash);
-        } else {
-            error!("Failed to mine block.");
+
+        outcome.into_inner().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::amount::Amount;
+    use crate::blockchain::transaction::Transaction;
+    use crate::blockchain::wallet::Address;
+
+    fn vector_transaction() -> Transaction {
+        Transaction::new(Address::from([0xa1; 20]), Address::from([0xb0; 20]), Amount::from_coins(1), 1_700_000_000, Amount::from_base_units(1_000_000))
+    }
+
+    fn vector_block() -> Block {
+        let mut block = Block::new(1, String::new(), BlockID::default());
+        block.timestamp = 1_700_000_000;
+        block.nonce = 42;
+        block.difficulty = 3;
+        block.set_transactions(vec![vector_transaction()]);
+        block
+    }
+
+    #[test]
+    fn transaction_id_vector() {
+        let transaction = vector_transaction();
+        assert_eq!(
+            hex::encode(transaction.to_bytes()),
+            "3fa1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a13fb0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b00000000005f5e10000000000000f4240000000006553f100000000"
+        );
+        assert_eq!(transaction.id().as_hex(), "0738c50759c04f60fd9c0967e1ca35d33ae88c2b16618abc431bb3640dea7d73");
+        // A single transaction's ID is the Merkle root.
+        assert_eq!(hex::encode(vector_block().merkle_root), transaction.id().as_hex());
+    }
+
+    #[test]
+    fn header_encoding_vector() {
+        let header = BlockHeader::from_block(&vector_block());
+        assert_eq!(
+            hex::encode(header.encode()),
+            concat!(
+                "00000001",
+                "000000006553f100",
+                "00000003",
+                "0000000000000000000000000000000000000000000000000000000000000000",
+                "0738c50759c04f60fd9c0967e1ca35d33ae88c2b16618abc431bb3640dea7d73",
+                "000000000000002a",
+            )
+        );
+    }
+
+    #[test]
+    fn header_hash_vector() {
+        let block = vector_block();
+        assert_eq!(Hashing::new(block).calculate_hash().as_hex(), "e5084c1f00bb954c85afaadce8d469bceb2740c8c182facc439074e90bfcfed2");
+    }
+
+    #[test]
+    fn block_id_hex_round_trip() {
+        let id: BlockID = "e5084c1f00bb954c85afaadce8d469bceb2740c8c182facc439074e90bfcfed2".parse().unwrap();
+        assert_eq!(id.as_hex(), "e5084c1f00bb954c85afaadce8d469bceb2740c8c182facc439074e90bfcfed2");
+        assert_eq!(serde_json::to_string(&id).unwrap(), "\"e5084c1f00bb954c85afaadce8d469bceb2740c8c182facc439074e90bfcfed2\"");
+        assert!("0".parse::<BlockID>().is_err());
+        assert!("zz".repeat(32).parse::<BlockID>().is_err());
+    }
+
+    #[test]
+    fn block_merkle_root_hex_round_trip() {
+        let block = vector_block();
+        let mut json = serde_json::to_value(&block).unwrap();
+        assert_eq!(json["merkle_root"], hex::encode(block.merkle_root));
+        assert_eq!(serde_json::from_value::<Block>(json.clone()).unwrap(), block);
+
+        for bad in ["", "00", &"zz".repeat(32)] {
+            json["merkle_root"] = bad.into();
+            assert!(serde_json::from_value::<Block>(json.clone()).is_err());
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn mined_hash_matches_calculate_hash() {
+        let mut hashing = Hashing::new(vector_block());
+        let mined = hashing.mine_block(8, &CancelToken::new(), None).unwrap();
+        assert_eq!(hashing.block.difficulty, 8);
+        assert_eq!(hashing.calculate_hash(), hashing.block.hash);
+        assert_eq!(mined.hash, hashing.block.hash);
+        assert_eq!(mined.header, BlockHeader::from_block(&hashing.block));
+        assert!(Hashing::meets_difficulty(&hashing.block.hash, 8));
+    }
+
+    #[test]
+    fn moves_the_timestamp_when_the_nonce_range_runs_out() {
+        let block = vector_block();
+        let mut hashing = Hashing::new(block.clone()).with_nonce_range(0..2);
+        let mined = hashing.mine_block(4, &CancelToken::new(), None).unwrap();
+        assert!(mined.header.nonce < 2);
+        assert!(Hashing::meets_difficulty(&mined.hash, 4));
+        assert_eq!(mined.hash, mined.header.hash());
+        if mined.attempts > 2 {
+            assert!(hashing.block.timestamp > block.timestamp);
+        }
+    }
+
+    #[test]
+    fn stops_when_cancelled_or_out_of_time() {
+        let cancel = CancelToken::new();
+        cancel.cancel();
+        assert_eq!(Hashing::new(vector_block()).mine_block(8, &cancel, None), Err(MiningError::Cancelled));
+
+        let timeout = Duration::from_millis(50);
+        let result = Hashing::new(vector_block()).mine_block(64, &CancelToken::new(), Some(timeout));
+        assert_eq!(result, Err(MiningError::TimedOut(timeout)));
+    }
+}
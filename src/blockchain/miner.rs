@@ -0,0 +1,496 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::{info, warn};
+use serde::Serialize;
+use serde_with::{hex::Hex, serde_as};
+use thiserror::Error;
+
+use crate::blockchain::block::{Block, BlockID};
+use crate::blockchain::core::{ChainError, ChainUpdate, SharedBlockchain};
+use crate::blockchain::hashing::{BlockHeader, CancelToken, Hashing, MiningError};
+use crate::blockchain::merkle::MerkleHash;
+use crate::blockchain::p2p::core::Network;
+use crate::blockchain::p2p::peers::unix_time;
+use crate::blockchain::transaction::{Transaction, TransactionID};
+use crate::blockchain::transaction_pool::SharedTransactionPool;
+use crate::blockchain::wallet::Address;
+
+/// How often a running search checks whether it was stopped or its block
+/// went stale.
+const REFRESH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How many handed out templates are kept for `Miner::submit_header`.
+const MAX_TEMPLATES: usize = 64;
+
+#[derive(Debug, Error)]
+pub enum MinerError {
+    #[error("no payout address given and SERENITY_MINER_ADDRESS is not set")]
+    NoAddress,
+    #[error("the miner is already running")]
+    AlreadyRunning,
+    #[error("failed to start the mining threads: {0}")]
+    Threads(#[from] rayon::ThreadPoolBuildError),
+}
+
+/// Why a header from an external miner was not accepted.
+#[derive(Debug, Error)]
+pub enum SubmitError {
+    #[error("no template with merkle root {0}, it may have expired")]
+    UnknownTemplate(String),
+    #[error("header {field} does not match the template")]
+    HeaderMismatch { field: &'static str },
+    #[error("block rejected: {0}")]
+    Rejected(#[from] ChainError),
+}
+
+/// Who gets paid for mined blocks and how many threads search for them.
+#[derive(Debug, Clone)]
+pub struct MinerConfig {
+    pub address: Option<Address>,
+    pub threads: usize,
+}
+
+impl Default for MinerConfig {
+    fn default() -> MinerConfig {
+        MinerConfig {
+            address: None,
+            threads: std::thread::available_parallelism().map_or(1, |threads| threads.get()),
+        }
+    }
+}
+
+impl MinerConfig {
+    /// Reads the payout address from `SERENITY_MINER_ADDRESS` and the thread
+    /// count from `SERENITY_MINER_THREADS`, which defaults to the number of
+    /// CPUs.
+    pub fn from_env() -> MinerConfig {
+        let default = MinerConfig::default();
+        let address = std::env::var("SERENITY_MINER_ADDRESS").ok().and_then(|address| match address.parse() {
+            Ok(address) => Some(address),
+            Err(err) => {
+                warn!("Ignoring invalid SERENITY_MINER_ADDRESS {:?}: {}", address, err);
+                None
+            }
+        });
+        let threads = match std::env::var("SERENITY_MINER_THREADS") {
+            Ok(value) => match value.parse() {
+                Ok(threads) if threads > 0 => threads,
+                _ => {
+                    warn!("Ignoring invalid SERENITY_MINER_THREADS {:?}", value);
+                    default.threads
+                }
+            },
+            Err(_) => default.threads,
+        };
+        MinerConfig { address, threads }
+    }
+}
+
+/// What `GET /miner/status` shows.
+#[derive(Debug, Clone, Serialize)]
+pub struct MinerStatus {
+    pub running: bool,
+    pub address: Option<Address>,
+    pub threads: usize,
+    /// Height of the block currently being searched for.
+    pub height: Option<u32>,
+    /// Unix time the miner was last started.
+    pub started: Option<u64>,
+    pub blocks_mined: u64,
+    pub hashes: u64,
+}
+
+/// Everything an external miner needs to search for the next block. It
+/// hashes `header_prefix` followed by a big-endian 8 byte nonce with
+/// SHA-256 until the first 8 bytes, read as a big-endian number, are at or
+/// below `target`, then posts the header to `/submitblock`. The timestamp
+/// may be moved forward once the nonces run out.
+#[serde_as]
+#[derive(Debug, Clone, Serialize)]
+pub struct BlockTemplate {
+    pub index: u32,
+    pub timestamp: u64,
+    pub difficulty: u32,
+    pub prev_hash: BlockID,
+    #[serde_as(as = "Hex")]
+    pub merkle_root: MerkleHash,
+    #[serde_as(as = "Hex")]
+    pub target: [u8; 8],
+    #[serde_as(as = "Hex")]
+    pub header_prefix: Vec<u8>,
+    /// Pool transactions in block order, before the coinbase.
+    pub transactions: Vec<Transaction>,
+    /// The reward transactions that end the block.
+    pub coinbase: Vec<Transaction>,
+}
+
+impl BlockTemplate {
+    fn from_block(block: &Block) -> BlockTemplate {
+        let header = BlockHeader::from_block(block);
+        let (coinbase, transactions) = block.transactions.iter().cloned().partition(Transaction::is_reward);
+        BlockTemplate {
+            index: header.index,
+            timestamp: header.timestamp,
+            difficulty: header.difficulty,
+            prev_hash: header.prev_hash,
+            merkle_root: header.merkle_root,
+            target: Hashing::target(header.difficulty).to_be_bytes(),
+            header_prefix: header.encode_prefix(),
+            transactions,
+            coinbase,
+        }
+    }
+}
+
+/// A block found from a template, as returned by `/submitblock`.
+#[derive(Debug, Clone, Serialize)]
+pub struct SubmittedBlock {
+    pub index: u32,
+    pub hash: BlockID,
+    /// False when the block went on a side branch, e.g. because the tip
+    /// moved on while it was mined.
+    pub main_chain: bool,
+}
+
+/// A running background miner. `stop` ends it.
+#[derive(Debug)]
+struct Job {
+    address: Address,
+    threads: usize,
+    started: u64,
+    stop: CancelToken,
+}
+
+#[derive(Debug, Default)]
+struct MinerState {
+    job: Option<Job>,
+    height: Option<u32>,
+    blocks_mined: u64,
+    hashes: u64,
+    /// Unsolved blocks handed out to external miners, oldest first.
+    templates: Vec<Block>,
+}
+
+/// Mines blocks off the async runtime so the chain and pool locks are only
+/// held to build a block and to add it, never during the proof of work
+/// search. External miners get the same blocks through `block_template`
+/// and hand them back through `submit_header`. Clones share the same miner.
+///
+/// `state` is a plain mutex that is never held across an await, so it
+/// stays out of the blockchain, pool, sync, peers lock order.
+#[derive(Debug, Clone)]
+pub struct Miner {
+    blockchain: SharedBlockchain,
+    pool: SharedTransactionPool,
+    network: Network,
+    config: MinerConfig,
+    state: Arc<Mutex<MinerState>>,
+}
+
+/// How mining one block ended.
+enum Attempt {
+    /// Added on the main chain.
+    Mined(Block),
+    /// The tip or the pool changed, so the block was given up.
+    Stale,
+}
+
+impl Miner {
+    pub fn new(blockchain: SharedBlockchain, pool: SharedTransactionPool, network: Network, config: MinerConfig) -> Miner {
+        Miner { blockchain, pool, network, config, state: Arc::new(Mutex::new(MinerState::default())) }
+    }
+
+    /// Starts mining continuously in the background, paying `address` or
+    /// else the configured address, on `threads` or else the configured
+    /// number of threads.
+    pub fn start(&self, address: Option<Address>, threads: Option<usize>) -> Result<MinerStatus, MinerError> {
+        let address = address.or(self.config.address).ok_or(MinerError::NoAddress)?;
+        let threads = threads.unwrap_or(self.config.threads);
+        let mut state = self.state.lock().unwrap();
+        if state.job.is_some() {
+            return Err(MinerError::AlreadyRunning);
+        }
+        let workers = Arc::new(thread_pool(threads)?);
+        let stop = CancelToken::new();
+        state.job = Some(Job { address, threads, started: unix_time(), stop: stop.clone() });
+        drop(state);
+
+        info!("Started mining to {} on {} threads", address, threads);
+        tokio::spawn(self.clone().run(address, workers, stop));
+        Ok(self.status())
+    }
+
+    /// Stops the background miner. The current search ends within
+    /// `REFRESH_INTERVAL`.
+    pub fn stop(&self) -> MinerStatus {
+        let mut state = self.state.lock().unwrap();
+        if let Some(job) = state.job.take() {
+            job.stop.cancel();
+            state.height = None;
+            info!("Stopped mining to {}", job.address);
+        }
+        drop(state);
+        self.status()
+    }
+
+    pub fn status(&self) -> MinerStatus {
+        let state = self.state.lock().unwrap();
+        let job = state.job.as_ref();
+        MinerStatus {
+            running: job.is_some(),
+            address: job.map(|job| job.address).or(self.config.address),
+            threads: job.map_or(self.config.threads, |job| job.threads),
+            height: state.height,
+            started: job.map(|job| job.started),
+            blocks_mined: state.blocks_mined,
+            hashes: state.hashes,
+        }
+    }
+
+    /// Mines a single block paying `address` on the global rayon pool,
+    /// unless `cancel` is cancelled first. Returns the block once it's on
+    /// the main chain.
+    pub async fn mine_one(&self, address: &Address, cancel: &CancelToken) -> Result<Block, MiningError> {
+        loop {
+            if let Attempt::Mined(block) = self.attempt(address, None, cancel).await? {
+                return Ok(block);
+            }
+        }
+    }
+
+    /// Builds the next block paying `address` and remembers it, so a
+    /// solved header for it can be submitted later. Templates built on an
+    /// old tip are forgotten.
+    pub async fn block_template(&self, address: &Address) -> BlockTemplate {
+        self.block_template_paying(&[(*address, 1)]).await
+    }
+
+    /// Like `block_template`, with the reward split between `payouts` as in
+    /// `Blockchain::block_template_paying`.
+    pub async fn block_template_paying(&self, payouts: &[(Address, u64)]) -> BlockTemplate {
+        let block = {
+            let blockchain = self.blockchain.lock().await;
+            blockchain.block_template_paying(&*self.pool.lock().await, payouts)
+        };
+        let template = BlockTemplate::from_block(&block);
+
+        let mut state = self.state.lock().unwrap();
+        state.templates.retain(|known| known.prev_hash == block.prev_hash);
+        if state.templates.len() >= MAX_TEMPLATES {
+            state.templates.remove(0);
+        }
+        state.templates.push(block);
+        template
+    }
+
+    /// Completes the template with `header`'s merkle root using the
+    /// header's timestamp and nonce, and adds the block to the chain.
+    pub async fn submit_header(&self, header: &BlockHeader) -> Result<SubmittedBlock, SubmitError> {
+        let mut block = {
+            let state = self.state.lock().unwrap();
+            let template = state.templates.iter().find(|template| template.merkle_root == header.merkle_root);
+            template.cloned().ok_or_else(|| SubmitError::UnknownTemplate(hex::encode(header.merkle_root)))?
+        };
+        for (field, matches) in [
+            ("index", header.index == block.index),
+            ("prev_hash", header.prev_hash == block.prev_hash),
+            ("difficulty", header.difficulty == block.difficulty),
+        ] {
+            if !matches {
+                return Err(SubmitError::HeaderMismatch { field });
+            }
+        }
+        block.timestamp = header.timestamp;
+        block.nonce = header.nonce;
+        block.hash = header.hash();
+
+        let update = self.add_mined_block(&block).await?;
+        info!("Accepted block {} {} from an external miner", block.index, block.hash);
+        Ok(SubmittedBlock { index: block.index, hash: block.hash, main_chain: !update.connected.is_empty() })
+    }
+
+    /// Adds a block we or an external miner solved, and announces it to
+    /// peers if it's on the main chain.
+    async fn add_mined_block(&self, block: &Block) -> Result<ChainUpdate, ChainError> {
+        let mut blockchain = self.blockchain.lock().await;
+        let update = blockchain.submit_block(block.clone(), &mut *self.pool.lock().await).await?;
+        if !update.connected.is_empty() {
+            self.network.announce_block(block).await;
+        }
+        Ok(update)
+    }
+
+    async fn run(self, address: Address, workers: Arc<rayon::ThreadPool>, stop: CancelToken) {
+        // Without a timeout the search only ends early when stopped.
+        while self.attempt(&address, Some(&workers), &stop).await.is_ok() {}
+    }
+
+    /// Builds a block on the current tip and pool and searches for it on
+    /// `workers`, or the global rayon pool without them. The search is
+    /// restarted with a fresh block when the tip or the pool changes, and
+    /// given up when `cancel` is cancelled.
+    async fn attempt(
+        &self,
+        address: &Address,
+        workers: Option<&Arc<rayon::ThreadPool>>,
+        cancel: &CancelToken,
+    ) -> Result<Attempt, MiningError> {
+        if cancel.is_cancelled() {
+            return Err(MiningError::Cancelled);
+        }
+        let (block, pending) = {
+            let blockchain = self.blockchain.lock().await;
+            let pool = self.pool.lock().await;
+            (blockchain.block_template(&pool, address), pending_ids(&pool.pool))
+        };
+        let (height, difficulty, prev_hash) = (block.index, block.difficulty, block.prev_hash);
+        self.state.lock().unwrap().height = Some(height);
+
+        let search = CancelToken::new();
+        let mut handle = tokio::task::spawn_blocking({
+            let (workers, search) = (workers.cloned(), search.clone());
+            move || {
+                let mut hasher = Hashing::new(block);
+                let mined = match workers {
+                    Some(workers) => workers.install(|| hasher.mine_block(difficulty, &search, None)),
+                    None => hasher.mine_block(difficulty, &search, None),
+                };
+                mined.map(|mined| (hasher.block, mined.attempts))
+            }
+        });
+        let result = loop {
+            tokio::select! {
+                result = &mut handle => break result.expect("Mining thread panicked"),
+                _ = tokio::time::sleep(REFRESH_INTERVAL) => {
+                    if cancel.is_cancelled() || self.is_stale(&prev_hash, &pending).await {
+                        search.cancel();
+                    }
+                }
+            }
+        };
+
+        let (block, attempts) = match result {
+            Ok(mined) => mined,
+            Err(MiningError::Cancelled) if !cancel.is_cancelled() => return Ok(Attempt::Stale),
+            Err(err) => return Err(err),
+        };
+        self.state.lock().unwrap().hashes += attempts;
+
+        match self.add_mined_block(&block).await {
+            Ok(update) if !update.connected.is_empty() => {
+                self.state.lock().unwrap().blocks_mined += 1;
+                Ok(Attempt::Mined(block))
+            }
+            Ok(_) => Ok(Attempt::Stale),
+            Err(err) => {
+                warn!("Mined block {} was rejected: {}", height, err);
+                Ok(Attempt::Stale)
+            }
+        }
+    }
+
+    /// Whether the tip moved off `prev_hash` or the pool holds different
+    /// transactions than `pending` since the block was built.
+    async fn is_stale(&self, prev_hash: &BlockID, pending: &[TransactionID]) -> bool {
+        let blockchain = self.blockchain.lock().await;
+        let tip = blockchain.chain.last().map(|block| block.hash);
+        tip.as_ref() != Some(prev_hash) || pending_ids(&self.pool.lock().await.pool) != pending
+    }
+}
+
+fn pending_ids(pool: &[Transaction]) -> Vec<TransactionID> {
+    pool.iter().map(Transaction::id).collect()
+}
+
+fn thread_pool(threads: usize) -> Result<rayon::ThreadPool, rayon::ThreadPoolBuildError> {
+    rayon::ThreadPoolBuilder::new().num_threads(threads).thread_name(|index| format!("miner-{}", index)).build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::core::Blockchain;
+    use crate::blockchain::db::core::Store;
+    use crate::blockchain::db::memory::core::MemoryStore;
+    use crate::blockchain::p2p::core::P2pConfig;
+    use crate::blockchain::transaction_pool::TransactionPool;
+    use tokio::sync::Mutex as AsyncMutex;
+
+    async fn miner() -> Miner {
+        let store: Store = Arc::new(MemoryStore::new());
+        let blockchain = Arc::new(AsyncMutex::new(Blockchain::new(store.clone()).await.unwrap()));
+        let pool = Arc::new(AsyncMutex::new(TransactionPool::new(store.clone())));
+        let network = Network::new(blockchain.clone(), pool.clone(), store, P2pConfig::default());
+        Miner::new(blockchain, pool, network, MinerConfig { address: None, threads: 2 })
+    }
+
+    #[tokio::test]
+    async fn mines_in_the_background_until_stopped() {
+        let miner = miner().await;
+        assert!(matches!(miner.start(None, None), Err(MinerError::NoAddress)));
+
+        let address = Address::from([3; 20]);
+        let status = miner.start(Some(address), Some(1)).unwrap();
+        assert!(status.running);
+        assert_eq!(status.threads, 1);
+        assert!(matches!(miner.start(Some(address), None), Err(MinerError::AlreadyRunning)));
+
+        for _ in 0..200 {
+            if miner.status().blocks_mined >= 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(25)).await;
+        }
+        let status = miner.stop();
+        assert!(!status.running);
+        assert!(status.blocks_mined >= 2);
+
+        let blockchain = miner.blockchain.lock().await;
+        assert!(blockchain.chain.len() as u64 > status.blocks_mined);
+        assert!(blockchain.ledger.balance(&address).base_units() > 0);
+        blockchain.validate_chain().unwrap();
+    }
+
+    #[tokio::test]
+    async fn mine_one_gives_up_when_cancelled() {
+        let miner = miner().await;
+        let address = Address::from([4; 20]);
+        let block = miner.mine_one(&address, &CancelToken::new()).await.unwrap();
+        assert_eq!(miner.blockchain.lock().await.chain.last(), Some(&block));
+
+        let cancel = CancelToken::new();
+        cancel.cancel();
+        assert_eq!(miner.mine_one(&address, &cancel).await, Err(MiningError::Cancelled));
+    }
+
+    #[tokio::test]
+    async fn accepts_solved_headers_for_its_templates() {
+        let miner = miner().await;
+        let template = miner.block_template(&Address::from([5; 20])).await;
+        assert_eq!(template.coinbase[0].receiver, Address::from([5; 20]));
+
+        // Solve it the way an external miner would, from the template alone.
+        let mut header = BlockHeader {
+            index: template.index,
+            timestamp: template.timestamp,
+            difficulty: template.difficulty,
+            prev_hash: template.prev_hash,
+            merkle_root: template.merkle_root,
+            nonce: 0,
+        };
+        assert_eq!(header.encode_prefix(), template.header_prefix);
+        let target = u64::from_be_bytes(template.target);
+        while u64::from_be_bytes(header.hash().as_ref()[0..8].try_into().unwrap()) > target {
+            header.nonce += 1;
+        }
+
+        let submitted = miner.submit_header(&header).await.unwrap();
+        assert!(submitted.main_chain);
+        assert_eq!(miner.blockchain.lock().await.chain.last().unwrap().hash, submitted.hash);
+        assert!(matches!(miner.submit_header(&header).await, Err(SubmitError::Rejected(ChainError::Duplicate { .. }))));
+
+        header.merkle_root = [0; 32];
+        assert!(matches!(miner.submit_header(&header).await, Err(SubmitError::UnknownTemplate(_))));
+    }
+}
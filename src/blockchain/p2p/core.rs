@@ -0,0 +1,717 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+use thiserror::Error;
+use tokio::io::BufReader;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Mutex, Notify};
+
+use crate::blockchain::block::{Block, BlockID};
+use crate::blockchain::core::{Blockchain, SharedBlockchain};
+use crate::blockchain::hashing::BlockHeader;
+use crate::blockchain::db::core::Store;
+use crate::blockchain::p2p::message::{read_message, write_message, InvItem, Message, MAX_HEADERS, PROTOCOL_VERSION};
+use crate::blockchain::p2p::peers::{unix_time, AdmissionError, Misbehavior, Peer, PeerList, PeerManager};
+use crate::blockchain::p2p::sync::SyncState;
+use crate::blockchain::transaction::{Transaction, TransactionID};
+use crate::blockchain::transaction_pool::{SharedTransactionPool, TransactionPoolError};
+
+/// How long a peer gets to complete the version handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Wait between attempts to (re)connect to a configured peer.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// How often to look for a peer to sync from and retry stalled block
+/// requests.
+const SYNC_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Messages a peer may send per second before it counts as flooding.
+const MAX_MESSAGES_PER_SECOND: u32 = 500;
+
+const DEFAULT_MAX_INBOUND: usize = 16;
+const DEFAULT_MAX_OUTBOUND: usize = 8;
+
+#[derive(Debug, Error)]
+pub enum PeerError {
+    #[error("i/o error: {0}")]
+    Io(#[from] io::Error),
+    #[error("handshake failed: {0}")]
+    Handshake(String),
+    #[error("connected to ourselves")]
+    SelfConnection,
+    #[error("refused: {0}")]
+    Refused(#[from] AdmissionError),
+    #[error("peer {0}")]
+    Misbehaving(#[from] Misbehavior),
+    #[error("banned for serving invalid data")]
+    Banned,
+}
+
+/// Where to listen for peers, which peers to dial and how many
+/// connections to allow in each direction.
+#[derive(Debug, Clone)]
+pub struct P2pConfig {
+    pub listen: Option<SocketAddr>,
+    pub peers: Vec<String>,
+    pub max_inbound: usize,
+    pub max_outbound: usize,
+}
+
+impl Default for P2pConfig {
+    fn default() -> P2pConfig {
+        P2pConfig {
+            listen: None,
+            peers: vec![],
+            max_inbound: DEFAULT_MAX_INBOUND,
+            max_outbound: DEFAULT_MAX_OUTBOUND,
+        }
+    }
+}
+
+impl P2pConfig {
+    /// Reads `SERENITY_P2P_LISTEN` (e.g. `127.0.0.1:9000`), the comma
+    /// separated `SERENITY_P2P_PEERS` and the connection caps
+    /// `SERENITY_P2P_MAX_INBOUND` and `SERENITY_P2P_MAX_OUTBOUND`.
+    /// Networking stays off when neither of the first two is set.
+    pub fn from_env() -> P2pConfig {
+        let listen = std::env::var("SERENITY_P2P_LISTEN").ok().and_then(|listen| match listen.parse() {
+            Ok(addr) => Some(addr),
+            Err(err) => {
+                warn!("Ignoring invalid SERENITY_P2P_LISTEN {:?}: {}", listen, err);
+                None
+            }
+        });
+        let peers = std::env::var("SERENITY_P2P_PEERS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|peer| !peer.is_empty())
+            .map(str::to_string)
+            .collect();
+        let max = |name: &str, default: usize| match std::env::var(name) {
+            Ok(value) => value.parse().unwrap_or_else(|err| {
+                warn!("Ignoring invalid {} {:?}: {}", name, value, err);
+                default
+            }),
+            Err(_) => default,
+        };
+        P2pConfig {
+            listen,
+            peers,
+            max_inbound: max("SERENITY_P2P_MAX_INBOUND", DEFAULT_MAX_INBOUND),
+            max_outbound: max("SERENITY_P2P_MAX_OUTBOUND", DEFAULT_MAX_OUTBOUND),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.listen.is_some() || !self.peers.is_empty()
+    }
+}
+
+/// Relays blocks and pool transactions between this node and its peers.
+/// Clones share the same peers.
+///
+/// Locks are always taken in the order blockchain, pool, sync, peers.
+#[derive(Debug, Clone)]
+pub struct Network {
+    blockchain: SharedBlockchain,
+    pool: SharedTransactionPool,
+    config: P2pConfig,
+    nonce: u64,
+    peers: Arc<Mutex<PeerManager>>,
+    sync: Arc<Mutex<SyncState>>,
+}
+
+impl Network {
+    pub fn new(blockchain: SharedBlockchain, pool: SharedTransactionPool, db: Store, config: P2pConfig) -> Network {
+        let peers = PeerManager::new(db, config.max_inbound, config.max_outbound);
+        Network {
+            blockchain,
+            pool,
+            config,
+            nonce: rand::random(),
+            peers: Arc::new(Mutex::new(peers)),
+            sync: Arc::new(Mutex::new(SyncState::default())),
+        }
+    }
+
+    /// Starts listening and dialing the configured and previously known
+    /// peers in the background. Returns the bound listen address, if any.
+    pub async fn start(&self) -> io::Result<Option<SocketAddr>> {
+        self.peers.lock().await.load().await;
+        let local = match self.config.listen {
+            Some(addr) => {
+                let listener = TcpListener::bind(addr).await?;
+                let local = listener.local_addr()?;
+                info!("Listening for peers on {}", local);
+                tokio::spawn(self.clone().accept_peers(listener));
+                Some(local)
+            }
+            None => None,
+        };
+        for peer in &self.config.peers {
+            tokio::spawn(self.clone().maintain_outbound(peer.clone()));
+        }
+        tokio::spawn(self.clone().dial_known_peers());
+        tokio::spawn(self.clone().drive_sync());
+        Ok(local)
+    }
+
+    /// Addresses of the peers that completed the handshake.
+    #[allow(dead_code)]
+    pub async fn peer_addrs(&self) -> Vec<SocketAddr> {
+        self.peers.lock().await.iter().map(|(addr, _)| *addr).collect()
+    }
+
+    /// Connected peers and every address we know of, for `GET /peers`.
+    pub async fn peer_list(&self) -> PeerList {
+        self.peers.lock().await.list()
+    }
+
+    /// Tells every peer about a block this node added to its chain.
+    pub async fn announce_block(&self, block: &Block) {
+        self.broadcast(Message::Inv { items: vec![InvItem::Block(block.hash)] }, None).await;
+    }
+
+    /// Tells every peer about a transaction this node accepted into its pool.
+    pub async fn announce_transaction(&self, id: TransactionID) {
+        self.broadcast(Message::Inv { items: vec![InvItem::Tx(id)] }, None).await;
+    }
+
+    async fn broadcast(&self, message: Message, except: Option<SocketAddr>) {
+        for (addr, peer) in self.peers.lock().await.iter() {
+            if Some(*addr) != except {
+                let _ = peer.sender.send(message.clone());
+            }
+        }
+    }
+
+    async fn accept_peers(self, listener: TcpListener) {
+        loop {
+            match listener.accept().await {
+                Ok((stream, addr)) => {
+                    let network = self.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) = network.run_connection(stream, addr, false).await {
+                            warn!("Peer {} disconnected: {}", addr, err);
+                        }
+                    });
+                }
+                Err(err) => warn!("Failed to accept peer: {}", err),
+            }
+        }
+    }
+
+    /// Keeps an outbound connection to `peer` open, reconnecting after
+    /// failures.
+    async fn maintain_outbound(self, peer: String) {
+        loop {
+            match TcpStream::connect(&peer).await {
+                Ok(stream) => {
+                    let addr = stream.peer_addr().expect("Connected stream has a peer address");
+                    match self.run_connection(stream, addr, true).await {
+                        Ok(()) => info!("Peer {} closed the connection", peer),
+                        Err(PeerError::SelfConnection) => {
+                            info!("Not connecting to {}, it is this node", peer);
+                            return;
+                        }
+                        Err(PeerError::Refused(err)) => debug!("Not connecting to {}: {}", peer, err),
+                        Err(err) => warn!("Peer {} disconnected: {}", peer, err),
+                    }
+                }
+                Err(err) => debug!("Failed to connect to {}: {}", peer, err),
+            }
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    }
+
+    /// Fills free outbound slots with addresses we connected to before.
+    async fn dial_known_peers(self) {
+        loop {
+            let candidates = self.peers.lock().await.dial_candidates(unix_time());
+            for addr in candidates {
+                let network = self.clone();
+                tokio::spawn(async move {
+                    let stream = TcpStream::connect(addr).await;
+                    network.peers.lock().await.finish_dialing(&addr);
+                    let result = match stream {
+                        Ok(stream) => network.run_connection(stream, addr, true).await,
+                        Err(err) => Err(err.into()),
+                    };
+                    if let Err(err) = result {
+                        debug!("Known peer {} disconnected: {}", addr, err);
+                    }
+                });
+            }
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    }
+
+    async fn run_connection(&self, stream: TcpStream, addr: SocketAddr, outbound: bool) -> Result<(), PeerError> {
+        self.peers.lock().await.admit(addr, outbound, unix_time())?;
+        let (read, mut write) = stream.into_split();
+        let mut reader = BufReader::new(read);
+        let (sender, mut outgoing) = mpsc::unbounded_channel();
+        let writer = tokio::spawn(async move {
+            while let Some(message) = outgoing.recv().await {
+                if let Err(err) = write_message(&mut write, &message).await {
+                    debug!("Failed to write to peer {}: {}", addr, err);
+                    break;
+                }
+            }
+        });
+
+        let _ = sender.send(self.version_message().await);
+        let result = self.serve_peer(&mut reader, addr, outbound, &sender).await;
+        self.peers.lock().await.remove(&addr);
+        self.sync.lock().await.peer_disconnected(addr);
+        writer.abort();
+        result
+    }
+
+    async fn version_message(&self) -> Message {
+        let blockchain = self.blockchain.lock().await;
+        Message::Version {
+            version: PROTOCOL_VERSION,
+            genesis: blockchain.chain[0].hash,
+            height: blockchain.chain.last().map(|block| block.index).unwrap_or_default(),
+            nonce: self.nonce,
+        }
+    }
+
+    async fn genesis(&self) -> BlockID {
+        self.blockchain.lock().await.chain[0].hash
+    }
+
+    async fn serve_peer(
+        &self,
+        reader: &mut BufReader<tokio::net::tcp::OwnedReadHalf>,
+        addr: SocketAddr,
+        outbound: bool,
+        sender: &mpsc::UnboundedSender<Message>,
+    ) -> Result<(), PeerError> {
+        let height = tokio::time::timeout(HANDSHAKE_TIMEOUT, self.handshake(reader, sender))
+            .await
+            .map_err(|_| PeerError::Handshake("timed out".to_string()))??;
+        let disconnect = Arc::new(Notify::new());
+        let peer = Peer { height, outbound, score: 0, sender: sender.clone(), disconnect: disconnect.clone() };
+        self.peers.lock().await.register(addr, peer, unix_time()).await?;
+        info!("Connected to {} peer {} at height {}", if outbound { "outbound" } else { "inbound" }, addr, height);
+        if height > self.height().await {
+            self.begin_sync(addr).await;
+        }
+
+        let mut window = Instant::now();
+        let mut received = 0;
+        loop {
+            let message = tokio::select! {
+                message = read_message(reader) => message?,
+                _ = disconnect.notified() => return Err(PeerError::Banned),
+            };
+            let Some(message) = message else {
+                return Ok(());
+            };
+
+            if window.elapsed() >= Duration::from_secs(1) {
+                window = Instant::now();
+                received = 0;
+            }
+            received += 1;
+            let result = match received > MAX_MESSAGES_PER_SECOND {
+                true => Err(Misbehavior::Flooding(MAX_MESSAGES_PER_SECOND).into()),
+                false => self.handle_message(addr, message, sender).await,
+            };
+            match result {
+                Err(PeerError::Misbehaving(misbehavior)) => {
+                    if self.penalize(addr, &misbehavior).await {
+                        return Err(misbehavior.into());
+                    }
+                }
+                result => result?,
+            }
+        }
+    }
+
+    /// Scores `misbehavior` against the peer at `addr`, which bans and
+    /// disconnects it once it has done too much. Returns whether it did.
+    async fn penalize(&self, addr: SocketAddr, misbehavior: &Misbehavior) -> bool {
+        self.peers.lock().await.penalize(addr, misbehavior, unix_time()).await
+    }
+
+    async fn height(&self) -> u32 {
+        self.blockchain.lock().await.chain.last().map(|block| block.index).unwrap_or_default()
+    }
+
+    /// Checks the peer's `Version`, acknowledges it and waits for the peer
+    /// to acknowledge ours. Returns the peer's height.
+    async fn handshake(
+        &self,
+        reader: &mut BufReader<tokio::net::tcp::OwnedReadHalf>,
+        sender: &mpsc::UnboundedSender<Message>,
+    ) -> Result<u32, PeerError> {
+        let Some(Message::Version { version, genesis, height, nonce }) = read_message(reader).await? else {
+            return Err(PeerError::Handshake("expected a version message".to_string()));
+        };
+        if nonce == self.nonce {
+            return Err(PeerError::SelfConnection);
+        }
+        if version != PROTOCOL_VERSION {
+            return Err(PeerError::Handshake(format!("unsupported protocol version {}", version)));
+        }
+        if genesis != self.genesis().await {
+            return Err(PeerError::Handshake(format!("peer is on a different chain with genesis {}", genesis)));
+        }
+        let _ = sender.send(Message::Verack);
+
+        match read_message(reader).await? {
+            Some(Message::Verack) => Ok(height),
+            _ => Err(PeerError::Handshake("expected a verack message".to_string())),
+        }
+    }
+
+    async fn handle_message(&self, addr: SocketAddr, message: Message, sender: &mpsc::UnboundedSender<Message>) -> Result<(), PeerError> {
+        match message {
+            Message::Version { .. } => return Err(Misbehavior::Unexpected("version").into()),
+            Message::Verack => return Err(Misbehavior::Unexpected("verack").into()),
+            Message::Inv { items } => {
+                let mut wanted = vec![];
+                for item in items {
+                    if !self.is_known(&item).await {
+                        wanted.push(item);
+                    }
+                }
+                if !wanted.is_empty() {
+                    let _ = sender.send(Message::GetData { items: wanted });
+                }
+            }
+            Message::GetData { items } => {
+                for item in items {
+                    if let Some(message) = self.lookup(&item).await {
+                        let _ = sender.send(message);
+                    }
+                }
+            }
+            Message::Block { block } => self.handle_block(addr, block).await?,
+            Message::Tx { transaction } => self.handle_transaction(addr, transaction).await?,
+            Message::GetHeaders { locator } => {
+                let headers = self.blockchain.lock().await.headers_after(&locator, MAX_HEADERS);
+                let _ = sender.send(Message::Headers { headers });
+            }
+            Message::Headers { headers } => self.handle_headers(addr, headers).await?,
+        }
+        Ok(())
+    }
+
+    async fn is_known(&self, item: &InvItem) -> bool {
+        match item {
+            InvItem::Block(hash) => self.blockchain.lock().await.tree.contains(hash),
+            InvItem::Tx(id) => self.pool.lock().await.get(id).is_some(),
+        }
+    }
+
+    async fn lookup(&self, item: &InvItem) -> Option<Message> {
+        match item {
+            InvItem::Block(hash) => {
+                let blockchain = self.blockchain.lock().await;
+                let block = blockchain.block_by_hash(hash)?;
+                Some(Message::Block { block: block.clone() })
+            }
+            InvItem::Tx(id) => {
+                let transaction = self.pool.lock().await.get(id)?.clone();
+                Some(Message::Tx { transaction })
+            }
+        }
+    }
+
+    /// Adds a block whose parent we know, which may extend the tip, a side
+    /// branch or cause a reorganization, and relays it to the other peers
+    /// if our main chain changed. Blocks we requested while syncing are
+    /// queued instead, and a block with an unknown parent starts a sync
+    /// from its sender.
+    async fn handle_block(&self, from: SocketAddr, block: Block) -> Result<(), PeerError> {
+        if self.sync.lock().await.receive(&block, from) {
+            self.apply_synced_blocks().await;
+            return Ok(());
+        }
+
+        let mut blockchain = self.blockchain.lock().await;
+        if blockchain.tree.contains(&block.hash) {
+            return Ok(());
+        }
+        if !blockchain.tree.contains(&block.prev_hash) {
+            drop(blockchain);
+            debug!("Parent of block {} from {} is unknown", block.index, from);
+            if let Some(peer) = self.peers.lock().await.get_mut(&from) {
+                peer.height = peer.height.max(block.index);
+            }
+            self.begin_sync(from).await;
+            return Ok(());
+        }
+        let update = match blockchain.add_block(block.clone()).await {
+            Ok(update) => update,
+            Err(err) => {
+                debug!("Rejected block {} from {}: {}", block.index, from, err);
+                return match Misbehavior::for_block(block.index, &err) {
+                    Some(misbehavior) => Err(misbehavior.into()),
+                    None => Ok(()),
+                };
+            }
+        };
+        self.pool.lock().await.apply_update(&update, &blockchain.ledger);
+        drop(blockchain);
+
+        if let Some(peer) = self.peers.lock().await.get_mut(&from) {
+            peer.height = peer.height.max(block.index);
+        }
+        if update.connected.is_empty() {
+            debug!("Added side branch block {} from {}", block.index, from);
+            return Ok(());
+        }
+        info!("Added block {} from {}", block.index, from);
+        self.broadcast(Message::Inv { items: vec![InvItem::Block(block.hash)] }, Some(from)).await;
+        Ok(())
+    }
+
+    /// Starts a headers-first sync from `addr` unless one is running.
+    async fn begin_sync(&self, addr: SocketAddr) {
+        let locator = self.blockchain.lock().await.locator();
+        if !self.sync.lock().await.start(addr) {
+            return;
+        }
+        info!("Syncing from peer {}", addr);
+        if let Some(peer) = self.peers.lock().await.get(&addr) {
+            let _ = peer.sender.send(Message::GetHeaders { locator });
+        }
+    }
+
+    /// Validates headers from the sync peer, queues their blocks for
+    /// download and asks for more headers after a full batch.
+    async fn handle_headers(&self, from: SocketAddr, headers: Vec<BlockHeader>) -> Result<(), PeerError> {
+        let blockchain = self.blockchain.lock().await;
+        let mut sync = self.sync.lock().await;
+        if sync.peer() != Some(from) {
+            debug!("Ignoring unrequested headers from {}", from);
+            return Ok(());
+        }
+
+        // The first batch may start below a fork point or overlap blocks we
+        // already have; those are skipped and validation starts after them.
+        let known = match sync.recent_headers().is_empty() {
+            true => headers.iter().take_while(|header| blockchain.tree.contains(&header.hash())).count(),
+            false => 0,
+        };
+        let ancestors = match (sync.recent_headers(), headers.get(known)) {
+            (recent, _) if !recent.is_empty() => recent.to_vec(),
+            (_, Some(first)) if blockchain.tree.contains(&first.prev_hash) => blockchain.ancestor_headers(&first.prev_hash),
+            (_, Some(_)) => {
+                sync.reset();
+                return Err(Misbehavior::InvalidHeaders("they do not connect to our chain".to_string()).into());
+            }
+            (_, None) => vec![],
+        };
+        if known < headers.len() {
+            if let Err(err) = Blockchain::validate_headers(&ancestors, &headers[known..]) {
+                sync.reset();
+                return Err(Misbehavior::InvalidHeaders(err.to_string()).into());
+            }
+        }
+        drop(blockchain);
+
+        let more = sync.add_headers(&ancestors, &headers, known);
+        let mut peers = self.peers.lock().await;
+        let Some(peer) = peers.get_mut(&from) else {
+            return Ok(());
+        };
+        if let Some(last) = headers.last() {
+            debug!("Received headers up to {} from {}", last.index, from);
+            peer.height = peer.height.max(last.index);
+            if more {
+                let _ = peer.sender.send(Message::GetHeaders { locator: vec![last.hash()] });
+            }
+        }
+        drop(peers);
+        drop(sync);
+
+        self.request_blocks().await;
+        self.apply_synced_blocks().await;
+        Ok(())
+    }
+
+    /// Sends `GetData` for the next pending blocks, spread over every peer
+    /// that is high enough to have them.
+    async fn request_blocks(&self) {
+        let mut sync = self.sync.lock().await;
+        let peers = self.peers.lock().await;
+        let heights: Vec<(SocketAddr, u32)> = peers.iter().map(|(addr, peer)| (*addr, peer.height)).collect();
+        for (addr, hashes) in sync.next_requests(&heights, Instant::now()) {
+            if let Some(peer) = peers.get(&addr) {
+                let items = hashes.into_iter().map(InvItem::Block).collect();
+                let _ = peer.sender.send(Message::GetData { items });
+            }
+        }
+    }
+
+    /// Adds downloaded blocks to the chain in order for as long as the next
+    /// one has arrived. A block that fails validation abandons the sync and
+    /// drops the peer that sent it.
+    async fn apply_synced_blocks(&self) {
+        let mut blockchain = self.blockchain.lock().await;
+        let mut pool = self.pool.lock().await;
+        let mut sync = self.sync.lock().await;
+        while let Some((block, from)) = sync.next_ready() {
+            // It may have been relayed to us while we were downloading it.
+            if blockchain.tree.contains(&block.hash) {
+                continue;
+            }
+            match blockchain.add_block(block.clone()).await {
+                Ok(update) => pool.apply_update(&update, &blockchain.ledger),
+                Err(err) => {
+                    warn!("Abandoning sync, block {} from {} was rejected: {}", block.index, from, err);
+                    sync.reset();
+                    drop((blockchain, pool, sync));
+                    if let Some(misbehavior) = Misbehavior::for_block(block.index, &err) {
+                        self.penalize(from, &misbehavior).await;
+                    }
+                    return;
+                }
+            }
+        }
+        if sync.is_complete() {
+            let tip = blockchain.chain.last().expect("Chain has a genesis block");
+            info!("Synced to block {} from peer {}", tip.index, sync.peer().expect("Sync has a peer"));
+            sync.reset();
+        }
+        drop((blockchain, pool, sync));
+        self.request_blocks().await;
+    }
+
+    /// Periodically starts a sync from the highest peer when it is ahead
+    /// of us, and re-requests blocks that were not delivered in time.
+    async fn drive_sync(self) {
+        loop {
+            tokio::time::sleep(SYNC_INTERVAL).await;
+            let height = self.height().await;
+            let syncing = self.sync.lock().await.peer().is_some();
+            if syncing {
+                self.request_blocks().await;
+                continue;
+            }
+            let best = self
+                .peers
+                .lock()
+                .await
+                .iter()
+                .filter(|(_, peer)| peer.height > height)
+                .max_by_key(|(_, peer)| peer.height)
+                .map(|(addr, _)| *addr);
+            if let Some(addr) = best {
+                self.begin_sync(addr).await;
+            }
+        }
+    }
+
+    /// Adds a transaction to the pool and relays it to the other peers.
+    /// Transactions that can't be valid count against the peer; ones that
+    /// don't apply to our balances may just have raced a block.
+    async fn handle_transaction(&self, from: SocketAddr, transaction: Transaction) -> Result<(), PeerError> {
+        let blockchain = self.blockchain.lock().await;
+        let result = self.pool.lock().await.add_transaction(transaction, &blockchain.ledger).await;
+        drop(blockchain);
+        match result {
+            Ok(id) => {
+                debug!("Added transaction {} from {}", id, from);
+                self.broadcast(Message::Inv { items: vec![InvItem::Tx(id)] }, Some(from)).await;
+            }
+            Err(TransactionPoolError::Duplicate(_)) => {}
+            Err(TransactionPoolError::Invalid(err)) => return Err(Misbehavior::InvalidTransaction(err.to_string()).into()),
+            Err(err) => debug!("Rejected transaction from {}: {}", from, err),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::test_util::mine_block;
+    use ed25519_dalek::SigningKey;
+
+    use crate::blockchain::amount::Amount;
+    use crate::blockchain::core::Blockchain;
+    use crate::blockchain::db::core::Store;
+    use crate::blockchain::db::memory::core::MemoryStore;
+    use crate::blockchain::transaction_pool::TransactionPool;
+    use crate::blockchain::wallet::Address;
+    use crate::utils::calculations::calculate_fee;
+
+    async fn node(config: P2pConfig) -> Network {
+        let store: Store = Arc::new(MemoryStore::new());
+        let blockchain = Blockchain::new(store.clone()).await.unwrap();
+        let pool = TransactionPool::new(store.clone());
+        Network::new(Arc::new(Mutex::new(blockchain)), Arc::new(Mutex::new(pool)), store, config)
+    }
+
+    async fn eventually<F: std::future::Future<Output = bool>>(mut check: impl FnMut() -> F) {
+        for _ in 0..100 {
+            if check().await {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("condition not met in time");
+    }
+
+    #[tokio::test]
+    async fn relays_blocks_and_transactions() {
+        let listen = Some("127.0.0.1:0".parse().unwrap());
+        let a = node(P2pConfig { listen, ..P2pConfig::default() }).await;
+        let addr = a.start().await.unwrap().unwrap();
+        let b = node(P2pConfig { peers: vec![addr.to_string()], ..P2pConfig::default() }).await;
+        b.start().await.unwrap();
+        eventually(|| async { a.peer_addrs().await.len() == 1 }).await;
+
+        let miner_key = SigningKey::from_bytes(&[1; 32]);
+        let miner = Address::from_public_key(&miner_key.verifying_key());
+        {
+            let mut blockchain = a.blockchain.lock().await;
+            mine_block(&mut blockchain, &mut *a.pool.lock().await, &miner).await;
+            a.announce_block(blockchain.chain.last().unwrap()).await;
+        }
+        eventually(|| async { b.blockchain.lock().await.chain.len() == 2 }).await;
+        assert_eq!(b.blockchain.lock().await.ledger.balance(&miner), a.blockchain.lock().await.ledger.balance(&miner));
+
+        let balance = b.blockchain.lock().await.ledger.balance(&miner);
+        let amount = Amount::from_base_units(balance.base_units() / 2);
+        let mut transaction = Transaction::new(miner, Address::from([9; 20]), amount, 1_724_000_001, calculate_fee(amount));
+        transaction.sign(&miner_key);
+        let id = {
+            let blockchain = b.blockchain.lock().await;
+            b.pool.lock().await.add_transaction(transaction, &blockchain.ledger).await.unwrap()
+        };
+        b.announce_transaction(id).await;
+        eventually(|| async { a.pool.lock().await.get(&id).is_some() }).await;
+    }
+
+    #[tokio::test]
+    async fn syncs_a_fresh_node_from_its_peers() {
+        let listen = Some("127.0.0.1:0".parse().unwrap());
+        let a = node(P2pConfig { listen, ..P2pConfig::default() }).await;
+        let addr = a.start().await.unwrap().unwrap();
+        let miner = Address::from([7; 20]);
+        for _ in 0..5 {
+            let mut blockchain = a.blockchain.lock().await;
+            mine_block(&mut blockchain, &mut *a.pool.lock().await, &miner).await;
+        }
+
+        let b = node(P2pConfig { peers: vec![addr.to_string()], ..P2pConfig::default() }).await;
+        b.start().await.unwrap();
+        eventually(|| async { b.blockchain.lock().await.chain.len() == 6 }).await;
+        assert_eq!(b.blockchain.lock().await.chain, a.blockchain.lock().await.chain);
+        assert_eq!(b.blockchain.lock().await.ledger.balance(&miner), a.blockchain.lock().await.ledger.balance(&miner));
+        eventually(|| async { b.sync.lock().await.peer().is_none() }).await;
+    }
+}
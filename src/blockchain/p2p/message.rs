@@ -0,0 +1,116 @@
+use std::io;
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::blockchain::block::{Block, BlockID};
+use crate::blockchain::hashing::BlockHeader;
+use crate::blockchain::transaction::{Transaction, TransactionID};
+
+/// Bumped whenever a change to `Message` breaks older nodes.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Longest line a peer may send. Anything longer is treated as misbehaviour.
+pub const MAX_MESSAGE_SIZE: u64 = 8 * 1024 * 1024;
+
+/// Most headers sent in one `Headers` message. A full batch means the
+/// sender may have more.
+pub const MAX_HEADERS: usize = 2000;
+
+/// Something a node can announce and serve: a block by hash or a pool
+/// transaction by ID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "id", rename_all = "snake_case")]
+pub enum InvItem {
+    Block(BlockID),
+    Tx(TransactionID),
+}
+
+/// Peer to peer messages, sent as one JSON object per line.
+///
+/// A connection starts with both sides sending `Version` and answering the
+/// other's with `Verack`. After that, new blocks and transactions are
+/// announced with `Inv`, requested with `GetData` and delivered as `Block`
+/// and `Tx`.
+///
+/// A node that is behind asks for `GetHeaders` from a locator (see
+/// `Blockchain::locator`), checks the `Headers` it gets back and then
+/// fetches the bodies with `GetData`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Message {
+    Version {
+        version: u32,
+        genesis: BlockID,
+        height: u32,
+        /// Random per-process value used to detect connections to ourselves.
+        nonce: u64,
+    },
+    Verack,
+    Inv { items: Vec<InvItem> },
+    GetData { items: Vec<InvItem> },
+    Block { block: Block },
+    Tx { transaction: Transaction },
+    GetHeaders { locator: Vec<BlockID> },
+    Headers { headers: Vec<BlockHeader> },
+}
+
+/// Reads the next message. Returns `None` once the peer closes the
+/// connection cleanly.
+pub async fn read_message<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<Option<Message>> {
+    let mut line = String::new();
+    let read = (&mut *reader).take(MAX_MESSAGE_SIZE).read_line(&mut line).await?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with('\n') {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "message is too long or truncated"));
+    }
+    serde_json::from_str(&line)
+        .map(Some)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+pub async fn write_message<W: AsyncWrite + Unpin>(writer: &mut W, message: &Message) -> io::Result<()> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    writer.write_all(&line).await?;
+    writer.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn messages_round_trip_as_lines() {
+        let messages = vec![
+            Message::Version { version: PROTOCOL_VERSION, genesis: BlockID::from([1; 32]), height: 7, nonce: 42 },
+            Message::Verack,
+            Message::Inv { items: vec![InvItem::Block(BlockID::from([2; 32])), InvItem::Tx(TransactionID::from([3; 32]))] },
+            Message::GetHeaders { locator: vec![BlockID::from([4; 32])] },
+            Message::Headers {
+                headers: vec![BlockHeader {
+                    index: 1,
+                    timestamp: 1_724_000_000,
+                    difficulty: 1,
+                    prev_hash: BlockID::from([5; 32]),
+                    merkle_root: [6; 32],
+                    nonce: 7,
+                }],
+            },
+        ];
+        let mut buffer = vec![];
+        for message in &messages {
+            write_message(&mut buffer, message).await.unwrap();
+        }
+        assert_eq!(buffer.iter().filter(|&&byte| byte == b'\n').count(), messages.len());
+
+        let mut reader = buffer.as_slice();
+        for message in &messages {
+            assert_eq!(read_message(&mut reader).await.unwrap().as_ref(), Some(message));
+        }
+        assert_eq!(read_message(&mut reader).await.unwrap(), None);
+        assert!(read_message(&mut &b"{\"type\":\"verack\"}"[..]).await.is_err());
+    }
+}
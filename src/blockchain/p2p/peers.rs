@@ -0,0 +1,304 @@
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::{mpsc, Notify};
+
+use crate::blockchain::core::ChainError;
+use crate::blockchain::db::core::Store;
+use crate::blockchain::p2p::message::Message;
+
+/// Misbehaviour score at which a peer is banned.
+pub const BAN_THRESHOLD: u32 = 100;
+
+/// How long a ban lasts, in seconds.
+pub const BAN_DURATION: u64 = 24 * 60 * 60;
+
+pub fn unix_time() -> u64 {
+    chrono::Utc::now().timestamp() as u64
+}
+
+/// Something a peer did wrong. Each kind adds its `penalty` to the peer's
+/// score, and a peer is banned once the score reaches `BAN_THRESHOLD`.
+#[derive(Debug, Error)]
+pub enum Misbehavior {
+    #[error("sent invalid block {index}: {reason}")]
+    InvalidBlock { index: u32, reason: String },
+    #[error("sent block {index} that does not fit our chain: {reason}")]
+    RejectedBlock { index: u32, reason: String },
+    #[error("sent invalid headers: {0}")]
+    InvalidHeaders(String),
+    #[error("sent an invalid transaction: {0}")]
+    InvalidTransaction(String),
+    #[error("sent an unexpected {0} message")]
+    Unexpected(&'static str),
+    #[error("sent more than {0} messages in a second")]
+    Flooding(u32),
+}
+
+impl Misbehavior {
+    /// What a peer did wrong by sending block `index` that we couldn't add.
+    /// Only a block that is invalid on its own gets the peer banned at once,
+    /// and a block we already have or can't place yet isn't its fault.
+    pub fn for_block(index: u32, err: &ChainError) -> Option<Misbehavior> {
+        match err {
+            ChainError::Duplicate { .. } | ChainError::UnknownParent { .. } | ChainError::Empty | ChainError::Store(_) => None,
+            err if err.is_invalid_data() => Some(Misbehavior::InvalidBlock { index, reason: err.to_string() }),
+            err => Some(Misbehavior::RejectedBlock { index, reason: err.to_string() }),
+        }
+    }
+
+    pub fn penalty(&self) -> u32 {
+        match self {
+            Misbehavior::InvalidBlock { .. } | Misbehavior::InvalidHeaders(_) => BAN_THRESHOLD,
+            Misbehavior::RejectedBlock { .. } | Misbehavior::Unexpected(_) | Misbehavior::Flooding(_) => 20,
+            Misbehavior::InvalidTransaction(_) => 10,
+        }
+    }
+}
+
+/// What we remember about a peer address across restarts.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PeerRecord {
+    pub addr: SocketAddr,
+    /// Unix time of the last successful outbound connection. Only
+    /// addresses we have dialed before are dialed again.
+    pub last_connected: Option<u64>,
+    /// Unix time until which the address's IP is banned.
+    pub banned_until: Option<u64>,
+}
+
+/// A connected peer that completed the handshake.
+#[derive(Debug)]
+pub struct Peer {
+    pub height: u32,
+    pub outbound: bool,
+    pub score: u32,
+    pub sender: mpsc::UnboundedSender<Message>,
+    pub disconnect: Arc<Notify>,
+}
+
+/// A connected peer as shown by `GET /peers`.
+#[derive(Debug, Serialize)]
+pub struct PeerInfo {
+    pub addr: SocketAddr,
+    pub outbound: bool,
+    pub height: u32,
+    pub score: u32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PeerList {
+    pub connected: Vec<PeerInfo>,
+    pub known: Vec<PeerRecord>,
+}
+
+/// Why a connection was refused.
+#[derive(Debug, Error, PartialEq)]
+pub enum AdmissionError {
+    #[error("{0} is banned")]
+    Banned(IpAddr),
+    #[error("already at the limit of {0} {1} connections")]
+    Full(usize, &'static str),
+}
+
+/// Tracks connected peers, scores their misbehaviour, bans the worst and
+/// keeps inbound and outbound connections under their caps. Known
+/// addresses and bans are persisted in the store.
+#[derive(Debug)]
+pub struct PeerManager {
+    db: Store,
+    max_inbound: usize,
+    max_outbound: usize,
+    connected: HashMap<SocketAddr, Peer>,
+    records: HashMap<SocketAddr, PeerRecord>,
+    dialing: HashSet<SocketAddr>,
+}
+
+impl PeerManager {
+    pub fn new(db: Store, max_inbound: usize, max_outbound: usize) -> PeerManager {
+        PeerManager {
+            db,
+            max_inbound,
+            max_outbound,
+            connected: HashMap::new(),
+            records: HashMap::new(),
+            dialing: HashSet::new(),
+        }
+    }
+
+    /// Loads the known addresses and bans from the store.
+    pub async fn load(&mut self) {
+        match self.db.get_peers().await {
+            Ok(records) => {
+                info!("Loaded {} known peer addresses", records.len());
+                self.records = records.into_iter().map(|record| (record.addr, record)).collect();
+            }
+            Err(err) => warn!("Failed to load known peers: {}", err),
+        }
+    }
+
+    pub fn get(&self, addr: &SocketAddr) -> Option<&Peer> {
+        self.connected.get(addr)
+    }
+
+    pub fn get_mut(&mut self, addr: &SocketAddr) -> Option<&mut Peer> {
+        self.connected.get_mut(addr)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&SocketAddr, &Peer)> {
+        self.connected.iter()
+    }
+
+    pub fn is_banned(&self, ip: IpAddr, now: u64) -> bool {
+        self.records
+            .values()
+            .any(|record| record.addr.ip() == ip && record.banned_until.is_some_and(|until| until > now))
+    }
+
+    fn count(&self, outbound: bool) -> usize {
+        self.connected.values().filter(|peer| peer.outbound == outbound).count()
+    }
+
+    /// Checks that a connection to or from `addr` is allowed right now.
+    pub fn admit(&self, addr: SocketAddr, outbound: bool, now: u64) -> Result<(), AdmissionError> {
+        if self.is_banned(addr.ip(), now) {
+            return Err(AdmissionError::Banned(addr.ip()));
+        }
+        let (count, max, direction) = match outbound {
+            true => (self.count(true), self.max_outbound, "outbound"),
+            false => (self.count(false), self.max_inbound, "inbound"),
+        };
+        if count >= max {
+            return Err(AdmissionError::Full(max, direction));
+        }
+        Ok(())
+    }
+
+    /// Adds a peer that completed the handshake, unless a cap was reached
+    /// meanwhile. Outbound addresses are remembered for later dialing.
+    pub async fn register(&mut self, addr: SocketAddr, peer: Peer, now: u64) -> Result<(), AdmissionError> {
+        self.admit(addr, peer.outbound, now)?;
+        if peer.outbound {
+            let record = self.records.entry(addr).or_insert(PeerRecord { addr, last_connected: None, banned_until: None });
+            record.last_connected = Some(now);
+            let record = record.clone();
+            self.save(&record).await;
+        }
+        self.connected.insert(addr, peer);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, addr: &SocketAddr) {
+        self.connected.remove(addr);
+    }
+
+    /// Adds the penalty for `misbehavior` to the peer's score. Once it
+    /// reaches `BAN_THRESHOLD` the peer's IP is banned for `BAN_DURATION`
+    /// and the connection is dropped. Returns whether the peer was banned.
+    pub async fn penalize(&mut self, addr: SocketAddr, misbehavior: &Misbehavior, now: u64) -> bool {
+        let Some(peer) = self.connected.get_mut(&addr) else {
+            return false;
+        };
+        peer.score = peer.score.saturating_add(misbehavior.penalty());
+        warn!("Peer {} {} (score {})", addr, misbehavior, peer.score);
+        if peer.score < BAN_THRESHOLD {
+            return false;
+        }
+        peer.disconnect.notify_one();
+
+        let record = self.records.entry(addr).or_insert(PeerRecord { addr, last_connected: None, banned_until: None });
+        record.banned_until = Some(now + BAN_DURATION);
+        let record = record.clone();
+        warn!("Banned {} until {}", addr.ip(), now + BAN_DURATION);
+        self.save(&record).await;
+        true
+    }
+
+    async fn save(&self, record: &PeerRecord) {
+        if let Err(err) = self.db.save_peer(record).await {
+            warn!("Failed to save peer {}: {}", record.addr, err);
+        }
+    }
+
+    /// Known addresses to dial to fill the free outbound slots, which are
+    /// marked as being dialed until `finish_dialing`.
+    pub fn dial_candidates(&mut self, now: u64) -> Vec<SocketAddr> {
+        let free = self.max_outbound.saturating_sub(self.count(true) + self.dialing.len());
+        let mut candidates: Vec<&PeerRecord> = self
+            .records
+            .values()
+            .filter(|record| record.last_connected.is_some())
+            .filter(|record| !self.connected.contains_key(&record.addr) && !self.dialing.contains(&record.addr))
+            .filter(|record| !self.is_banned(record.addr.ip(), now))
+            .collect();
+        candidates.sort_by_key(|record| std::cmp::Reverse(record.last_connected));
+        let candidates: Vec<SocketAddr> = candidates.into_iter().take(free).map(|record| record.addr).collect();
+        self.dialing.extend(&candidates);
+        candidates
+    }
+
+    pub fn finish_dialing(&mut self, addr: &SocketAddr) {
+        self.dialing.remove(addr);
+    }
+
+    pub fn list(&self) -> PeerList {
+        let mut connected: Vec<PeerInfo> = self
+            .connected
+            .iter()
+            .map(|(addr, peer)| PeerInfo { addr: *addr, outbound: peer.outbound, height: peer.height, score: peer.score })
+            .collect();
+        connected.sort_by_key(|peer| peer.addr);
+        let mut known: Vec<PeerRecord> = self.records.values().cloned().collect();
+        known.sort_by_key(|record| record.addr);
+        PeerList { connected, known }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::db::memory::core::MemoryStore;
+
+    fn peer(outbound: bool) -> Peer {
+        let (sender, _) = mpsc::unbounded_channel();
+        Peer { height: 0, outbound, score: 0, sender, disconnect: Arc::new(Notify::new()) }
+    }
+
+    #[test]
+    fn only_bans_for_blocks_invalid_on_their_own() {
+        let bad_work = ChainError::InsufficientWork { index: 3, difficulty: 2 };
+        assert_eq!(Misbehavior::for_block(3, &bad_work).map(|misbehavior| misbehavior.penalty()), Some(BAN_THRESHOLD));
+        let regression = ChainError::TimestampRegression { index: 3, timestamp: 1, prev_timestamp: 2 };
+        assert!(Misbehavior::for_block(3, &regression).is_some_and(|misbehavior| misbehavior.penalty() < BAN_THRESHOLD));
+        assert!(Misbehavior::for_block(3, &ChainError::Duplicate { index: 3 }).is_none());
+    }
+
+    #[tokio::test]
+    async fn bans_misbehaving_peers_and_remembers_it() {
+        let store: Store = Arc::new(MemoryStore::new());
+        let mut manager = PeerManager::new(store.clone(), 1, 1);
+        let (inbound, outbound): (SocketAddr, SocketAddr) =
+            ("10.0.0.1:5000".parse().unwrap(), "10.0.0.2:9000".parse().unwrap());
+        manager.register(inbound, peer(false), 100).await.unwrap();
+        manager.register(outbound, peer(true), 100).await.unwrap();
+        assert_eq!(manager.admit("10.0.0.3:5000".parse().unwrap(), false, 100), Err(AdmissionError::Full(1, "inbound")));
+
+        let invalid = Misbehavior::InvalidTransaction("bad signature".to_string());
+        assert!(!manager.penalize(inbound, &invalid, 100).await);
+        let invalid = Misbehavior::InvalidBlock { index: 1, reason: "bad hash".to_string() };
+        assert!(manager.penalize(inbound, &invalid, 100).await);
+        manager.remove(&inbound);
+        assert_eq!(manager.admit("10.0.0.1:6000".parse().unwrap(), false, 100), Err(AdmissionError::Banned(inbound.ip())));
+        assert!(manager.admit("10.0.0.1:6000".parse().unwrap(), false, 100 + BAN_DURATION).is_ok());
+
+        let mut reloaded = PeerManager::new(store, 1, 1);
+        reloaded.load().await;
+        assert!(reloaded.is_banned(inbound.ip(), 100));
+        assert_eq!(reloaded.dial_candidates(100), vec![outbound]);
+        assert!(reloaded.dial_candidates(100).is_empty());
+    }
+}
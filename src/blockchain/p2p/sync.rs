@@ -0,0 +1,184 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::blockchain::block::{Block, BlockID};
+use crate::blockchain::hashing::BlockHeader;
+use crate::blockchain::p2p::message::MAX_HEADERS;
+use crate::utils::calculations::DIFFICULTY_ADJUSTMENT_INTERVAL;
+
+/// Most block bodies requested and not yet received at any time.
+pub const MAX_BLOCKS_IN_FLIGHT: usize = 128;
+
+/// After this long a requested block is asked for again, from another peer
+/// if there is one.
+pub const BLOCK_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Progress of a headers-first download.
+///
+/// Headers come from a single sync peer and are validated before they are
+/// queued. Their bodies are then requested from every peer high enough to
+/// have them and applied strictly in header order, so a block that arrives
+/// early waits in `received` until its parent is in the chain.
+#[derive(Debug, Default)]
+pub struct SyncState {
+    peer: Option<SocketAddr>,
+    /// Newest validated headers, oldest first and as many as difficulty
+    /// adjustment looks at. The next batch is validated on top of them.
+    recent_headers: Vec<BlockHeader>,
+    /// The sync peer has sent a short batch, so there are no more headers.
+    headers_done: bool,
+    /// Hashes and indexes of validated headers whose blocks are not in the
+    /// chain yet, oldest first.
+    pending: VecDeque<(BlockID, u32)>,
+    requested: HashMap<BlockID, (SocketAddr, Instant)>,
+    received: HashMap<BlockID, (Block, SocketAddr)>,
+}
+
+impl SyncState {
+    pub fn peer(&self) -> Option<SocketAddr> {
+        self.peer
+    }
+
+    pub fn recent_headers(&self) -> &[BlockHeader] {
+        &self.recent_headers
+    }
+
+    /// Starts syncing from `peer` unless a sync is already running.
+    pub fn start(&mut self, peer: SocketAddr) -> bool {
+        if self.peer.is_some() {
+            return false;
+        }
+        *self = SyncState { peer: Some(peer), ..SyncState::default() };
+        true
+    }
+
+    pub fn reset(&mut self) {
+        *self = SyncState::default();
+    }
+
+    /// Queues a batch of headers validated on top of `ancestors`, of which
+    /// the first `known` are already in our block tree and aren't
+    /// downloaded. Returns whether the sync peer may have more.
+    pub fn add_headers(&mut self, ancestors: &[BlockHeader], headers: &[BlockHeader], known: usize) -> bool {
+        self.pending.extend(headers[known..].iter().map(|header| (header.hash(), header.index)));
+        let mut recent: Vec<BlockHeader> = ancestors.iter().chain(&headers[known..]).cloned().collect();
+        recent.drain(..recent.len().saturating_sub(DIFFICULTY_ADJUSTMENT_INTERVAL));
+        self.recent_headers = recent;
+        self.headers_done = headers.len() < MAX_HEADERS;
+        !self.headers_done
+    }
+
+    /// Picks which pending blocks to request from which peers, given each
+    /// peer's height. Requests that timed out go to a different peer when
+    /// one is available.
+    pub fn next_requests(&mut self, peers: &[(SocketAddr, u32)], now: Instant) -> Vec<(SocketAddr, Vec<BlockID>)> {
+        let is_stale = |requested_at: &Instant| now.duration_since(*requested_at) >= BLOCK_REQUEST_TIMEOUT;
+        let mut in_flight = self.requested.values().filter(|(_, requested_at)| !is_stale(requested_at)).count();
+        let mut assignments: HashMap<SocketAddr, Vec<BlockID>> = HashMap::new();
+        let mut turn = 0;
+
+        for (hash, index) in &self.pending {
+            if in_flight >= MAX_BLOCKS_IN_FLIGHT {
+                break;
+            }
+            if self.received.contains_key(hash) {
+                continue;
+            }
+            let previous = match self.requested.get(hash) {
+                Some((_, requested_at)) if !is_stale(requested_at) => continue,
+                Some((peer, _)) => Some(*peer),
+                None => None,
+            };
+            let mut candidates: Vec<SocketAddr> =
+                peers.iter().filter(|(_, height)| height >= index).map(|(addr, _)| *addr).collect();
+            if candidates.len() > 1 {
+                candidates.retain(|addr| Some(*addr) != previous);
+            }
+            if candidates.is_empty() {
+                continue;
+            }
+            let peer = candidates[turn % candidates.len()];
+            turn += 1;
+            self.requested.insert(*hash, (peer, now));
+            assignments.entry(peer).or_default().push(*hash);
+            in_flight += 1;
+        }
+        assignments.into_iter().collect()
+    }
+
+    /// Keeps a block we asked for. Returns false for blocks that are not
+    /// part of this sync, which the caller handles as a normal relay.
+    pub fn receive(&mut self, block: &Block, from: SocketAddr) -> bool {
+        if self.requested.remove(&block.hash).is_none() {
+            return false;
+        }
+        self.received.insert(block.hash, (block.clone(), from));
+        true
+    }
+
+    /// The next block to apply, if its body has arrived, with the peer
+    /// that sent it.
+    pub fn next_ready(&mut self) -> Option<(Block, SocketAddr)> {
+        let (hash, _) = self.pending.front()?;
+        let ready = self.received.remove(hash)?;
+        self.pending.pop_front();
+        Some(ready)
+    }
+
+    /// Every header has been received and every block applied.
+    pub fn is_complete(&self) -> bool {
+        self.peer.is_some() && self.headers_done && self.pending.is_empty()
+    }
+
+    /// Forgets requests sent to `addr` so they go elsewhere, or abandons
+    /// the sync if `addr` was the sync peer.
+    pub fn peer_disconnected(&mut self, addr: SocketAddr) {
+        if self.peer == Some(addr) {
+            self.reset();
+        } else {
+            self.requested.retain(|_, (peer, _)| *peer != addr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(index: u32) -> BlockHeader {
+        BlockHeader {
+            index,
+            timestamp: 1_724_000_000,
+            difficulty: 0,
+            prev_hash: BlockID::default(),
+            merkle_root: [0; 32],
+            nonce: index as u64,
+        }
+    }
+
+    #[test]
+    fn spreads_requests_and_retries_stale_ones() {
+        let (a, b): (SocketAddr, SocketAddr) = ("127.0.0.1:1".parse().unwrap(), "127.0.0.1:2".parse().unwrap());
+        let mut sync = SyncState::default();
+        assert!(sync.start(a));
+        assert!(!sync.start(b));
+        let headers: Vec<BlockHeader> = (1..=4).map(header).collect();
+        assert!(!sync.add_headers(&[header(0)], &headers, 0));
+
+        // `b` only has the first two blocks.
+        let now = Instant::now();
+        let requests = sync.next_requests(&[(a, 4), (b, 2)], now);
+        assert_eq!(requests.iter().map(|(_, hashes)| hashes.len()).sum::<usize>(), 4);
+        assert!(requests.iter().all(|(addr, hashes)| *addr == a || hashes.iter().all(|hash| *hash != headers[3].hash())));
+        assert!(sync.next_requests(&[(a, 4), (b, 2)], now).is_empty());
+
+        let later = now + BLOCK_REQUEST_TIMEOUT;
+        let retried = sync.next_requests(&[(a, 4), (b, 2)], later);
+        assert_eq!(retried.iter().map(|(_, hashes)| hashes.len()).sum::<usize>(), 4);
+
+        sync.peer_disconnected(a);
+        assert_eq!(sync.peer(), None);
+        assert!(!sync.is_complete());
+    }
+}
@@ -0,0 +1,158 @@
+use std::io::{self, BufRead, Write};
+
+use log::info;
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::blockchain::block::Block;
+use crate::blockchain::core::{Blockchain, ChainError, LedgerState};
+use crate::blockchain::db::core::{Store, StoreError};
+use crate::blockchain::tree::BlockTree;
+use crate::utils::calculations;
+
+/// Blocks read from the store per query while exporting.
+const EXPORT_PAGE_SIZE: u32 = 500;
+
+#[derive(Debug, Error)]
+pub enum SnapshotError {
+    #[error("i/o error: {0}")]
+    Io(#[from] io::Error),
+    #[error("line {line}: invalid block: {source}")]
+    Parse { line: usize, source: serde_json::Error },
+    #[error("invalid block: {0}")]
+    Chain(#[from] ChainError),
+    #[error("store error: {0}")]
+    Store(#[from] StoreError),
+    #[error("block {index} conflicts with the stored block {stored}")]
+    Conflict { index: u32, stored: String },
+}
+
+/// What `Blockchain::import` did with the blocks it read.
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportReport {
+    pub imported: usize,
+    pub skipped: usize,
+}
+
+/// Snapshots are newline-delimited JSON: one `Block`, with its
+/// transactions, per line in chain order starting at genesis.
+impl Blockchain {
+    /// Streams every stored block to `writer`, a page at a time. Returns the
+    /// number of blocks written.
+    pub async fn export<W: Write>(&self, mut writer: W) -> Result<usize, SnapshotError> {
+        let mut exported = 0;
+        let mut from = 0;
+        loop {
+            let blocks = self.db.get_blocks_range(from, from.saturating_add(EXPORT_PAGE_SIZE)).await?;
+            if blocks.is_empty() {
+                break;
+            }
+            for block in &blocks {
+                serde_json::to_writer(&mut writer, block).map_err(io::Error::from)?;
+                writer.write_all(b"\n")?;
+            }
+            exported += blocks.len();
+            from = from.saturating_add(EXPORT_PAGE_SIZE);
+            info!("Exported {} blocks", exported);
+        }
+        writer.flush()?;
+        Ok(exported)
+    }
+
+    /// Loads a snapshot into `db`. Blocks already in the store must match
+    /// the snapshot and are skipped; every other block is validated against
+    /// its parent and the ledger before it's committed, so an invalid block
+    /// stops the import with everything before it kept.
+    pub async fn import<R: BufRead>(db: Store, reader: R) -> Result<(Blockchain, ImportReport), SnapshotError> {
+        let mut blockchain = Blockchain {
+            chain: db.get_blocks().await?,
+            difficulty: 0,
+            ledger: LedgerState::default(),
+            tree: BlockTree::default(),
+            db,
+        };
+        if !blockchain.chain.is_empty() {
+            blockchain.ledger = blockchain.validate_chain()?;
+            blockchain.tree = BlockTree::from_chain(&blockchain.chain);
+        }
+
+        let mut report = ImportReport { imported: 0, skipped: 0 };
+        for (line, text) in reader.lines().enumerate() {
+            let text = text?;
+            if text.trim().is_empty() {
+                continue;
+            }
+            let block: Block = serde_json::from_str(&text).map_err(|source| SnapshotError::Parse { line: line + 1, source })?;
+
+            if let Some(stored) = blockchain.chain.get(block.index as usize) {
+                if stored.hash != block.hash {
+                    return Err(SnapshotError::Conflict { index: block.index, stored: stored.hash.to_string() });
+                }
+                report.skipped += 1;
+            } else if blockchain.chain.is_empty() {
+                Blockchain::validate_genesis(&block)?;
+                let touched = blockchain
+                    .ledger
+                    .apply_block(&block)
+                    .map_err(|source| ChainError::Ledger { index: block.index, source })?;
+                blockchain.db.commit_block(&block, &touched).await?;
+                blockchain.tree.insert(&block, block.difficulty, true);
+                blockchain.chain.push(block);
+                report.imported += 1;
+            } else {
+                // Snapshots hold a single chain, so every block must extend
+                // the tip rather than start a side branch.
+                let tip = blockchain.chain.last().expect("Chain is not empty");
+                if block.prev_hash != tip.hash {
+                    return Err(ChainError::PrevHashMismatch { index: block.index, expected: tip.hash, found: block.prev_hash }.into());
+                }
+                blockchain.add_block(block).await?;
+                report.imported += 1;
+            }
+        }
+
+        blockchain.difficulty = calculations::calculate_difficulty(&blockchain.chain);
+        info!("Imported {} blocks, skipped {} already stored", report.imported, report.skipped);
+        Ok((blockchain, report))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::test_util::mine_block;
+    use std::sync::Arc;
+
+    use crate::blockchain::db::memory::core::MemoryStore;
+    use crate::blockchain::transaction_pool::TransactionPool;
+    use crate::blockchain::wallet::Address;
+
+    #[tokio::test]
+    async fn export_import_round_trip() {
+        let source: Store = Arc::new(MemoryStore::new());
+        let mut blockchain = Blockchain::new(source.clone()).await.unwrap();
+        let mut pool = TransactionPool::new(source);
+        let miner = Address::from([4; 20]);
+        mine_block(&mut blockchain, &mut pool, &miner).await;
+        mine_block(&mut blockchain, &mut pool, &miner).await;
+
+        let mut snapshot = vec![];
+        assert_eq!(blockchain.export(&mut snapshot).await.unwrap(), 3);
+
+        let target: Store = Arc::new(MemoryStore::new());
+        let (imported, report) = Blockchain::import(target.clone(), snapshot.as_slice()).await.unwrap();
+        assert_eq!((report.imported, report.skipped), (3, 0));
+        assert_eq!(imported.chain, blockchain.chain);
+        assert_eq!(target.get_balance(&miner).await.unwrap(), blockchain.ledger.balance(&miner));
+
+        let (_, report) = Blockchain::import(target.clone(), snapshot.as_slice()).await.unwrap();
+        assert_eq!((report.imported, report.skipped), (0, 3));
+
+        let mut tampered = blockchain.chain[2].clone();
+        tampered.nonce = tampered.nonce.wrapping_add(1);
+        let mut lines: Vec<String> = blockchain.chain[..2].iter().map(|block| serde_json::to_string(block).unwrap()).collect();
+        lines.push(serde_json::to_string(&tampered).unwrap());
+        let result = Blockchain::import(Arc::new(MemoryStore::new()), lines.join("\n").as_bytes()).await;
+        assert!(matches!(result, Err(SnapshotError::Chain(ChainError::HashMismatch { index: 2, .. }))));
+    }
+}
@@ -0,0 +1,714 @@
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+use serde::Serialize;
+use thiserror::Error;
+use tokio::io::BufReader;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+use crate::blockchain::block::BlockID;
+use crate::blockchain::core::SharedBlockchain;
+use crate::blockchain::hashing::{BlockHeader, Hashing};
+use crate::blockchain::miner::Miner;
+use crate::blockchain::p2p::peers::unix_time;
+use crate::blockchain::stratum::message::{read_message, write_message, Job, Reply, Request};
+use crate::blockchain::wallet::Address;
+
+/// Each worker searches its own `2^EXTRANONCE_BITS` nonces, picked by its
+/// extra-nonce in the bits above.
+const EXTRANONCE_BITS: u32 = 40;
+
+/// Extra-nonces, and so workers, one pool can hand out at once. The top
+/// range is left out so that every range's end fits in a `u64`.
+const MAX_EXTRANONCES: u64 = (1 << (u64::BITS - EXTRANONCE_BITS)) - 1;
+
+const DEFAULT_SHARE_DIFFICULTY: u32 = 12;
+
+/// How often to check whether the tip moved and a new job is due.
+const TIP_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How long a job is handed out before it's rebuilt with the latest pool
+/// transactions and share counts.
+const JOB_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How many jobs on the current tip still take shares.
+const MAX_JOBS: usize = 16;
+
+/// How far ahead of our clock a share's timestamp may be, in seconds.
+const MAX_FUTURE_TIMESTAMP: u64 = 10 * 60;
+
+/// Why a share was rejected.
+#[derive(Debug, Error, PartialEq)]
+pub enum ShareError {
+    #[error("not subscribed")]
+    NotSubscribed,
+    #[error("already subscribed")]
+    AlreadySubscribed,
+    #[error("the pool has no free nonce ranges")]
+    PoolFull,
+    #[error("job {0} is unknown or stale")]
+    UnknownJob(u64),
+    #[error("nonce {0} is outside the worker's range")]
+    NonceOutOfRange(u64),
+    #[error("timestamp {0} is before the job's or too far ahead")]
+    InvalidTimestamp(u64),
+    #[error("duplicate share")]
+    Duplicate,
+    #[error("hash does not meet the share difficulty {0}")]
+    LowDifficulty(u32),
+}
+
+/// Where the pool listens, who is paid before any worker has submitted a
+/// share, and how hard shares are. The share difficulty is capped at the
+/// block difficulty.
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    pub listen: Option<SocketAddr>,
+    pub address: Option<Address>,
+    pub share_difficulty: u32,
+}
+
+impl Default for PoolConfig {
+    fn default() -> PoolConfig {
+        PoolConfig { listen: None, address: None, share_difficulty: DEFAULT_SHARE_DIFFICULTY }
+    }
+}
+
+impl PoolConfig {
+    /// Reads `SERENITY_POOL_LISTEN` (e.g. `0.0.0.0:3333`),
+    /// `SERENITY_POOL_ADDRESS`, which defaults to `SERENITY_MINER_ADDRESS`,
+    /// and `SERENITY_POOL_SHARE_DIFFICULTY`. The pool stays off without a
+    /// listen address and a payout address.
+    pub fn from_env() -> PoolConfig {
+        let listen = std::env::var("SERENITY_POOL_LISTEN").ok().and_then(|listen| match listen.parse() {
+            Ok(addr) => Some(addr),
+            Err(err) => {
+                warn!("Ignoring invalid SERENITY_POOL_LISTEN {:?}: {}", listen, err);
+                None
+            }
+        });
+        let share_difficulty = match std::env::var("SERENITY_POOL_SHARE_DIFFICULTY") {
+            Ok(value) => value.parse().unwrap_or_else(|err| {
+                warn!("Ignoring invalid SERENITY_POOL_SHARE_DIFFICULTY {:?}: {}", value, err);
+                DEFAULT_SHARE_DIFFICULTY
+            }),
+            Err(_) => DEFAULT_SHARE_DIFFICULTY,
+        };
+        let address = ["SERENITY_POOL_ADDRESS", "SERENITY_MINER_ADDRESS"].into_iter().find_map(|name| {
+            let address = std::env::var(name).ok()?;
+            address.parse().inspect_err(|err| warn!("Ignoring invalid {} {:?}: {}", name, address, err)).ok()
+        });
+        if listen.is_some() && address.is_none() {
+            warn!("Not starting the mining pool without SERENITY_POOL_ADDRESS or SERENITY_MINER_ADDRESS");
+        }
+        PoolConfig { listen, address, share_difficulty }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.listen.is_some() && self.address.is_some()
+    }
+}
+
+/// A connected worker as shown by `GET /pool`.
+#[derive(Debug, Clone, Serialize)]
+pub struct WorkerInfo {
+    pub name: String,
+    pub address: Address,
+    pub nonce_start: u64,
+    pub accepted: u64,
+    pub rejected: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PoolStatus {
+    pub workers: Vec<WorkerInfo>,
+    /// Shares per payout address since the pool last found a block. The
+    /// next block's reward is split in these proportions.
+    pub round_shares: Vec<(Address, u64)>,
+    pub blocks_found: u64,
+}
+
+#[derive(Debug)]
+struct Worker {
+    name: String,
+    address: Address,
+    nonce_range: Range<u64>,
+    sender: mpsc::UnboundedSender<Reply>,
+    accepted: u64,
+    rejected: u64,
+}
+
+/// A handed out job, the round shares its reward pays for and the shares
+/// already submitted for it.
+#[derive(Debug)]
+struct OpenJob {
+    job: Job,
+    paid: Vec<(Address, u64)>,
+    seen: HashSet<(u64, u64)>,
+}
+
+#[derive(Debug, Default)]
+struct PoolState {
+    /// Subscribed workers by extra-nonce.
+    workers: HashMap<u64, Worker>,
+    next_extranonce: u64,
+    /// Extra-nonces of workers that left, handed out again before new ones.
+    free_extranonces: BTreeSet<u64>,
+    /// Jobs on the current tip, oldest first.
+    jobs: VecDeque<OpenJob>,
+    next_job_id: u64,
+    job_created: Option<Instant>,
+    /// Shares per payout address in the current round. They outlive the
+    /// connection that submitted them.
+    round: HashMap<Address, u64>,
+    blocks_found: u64,
+}
+
+impl PoolState {
+    /// The round's shares so far, which the next job pays for.
+    fn round_shares(&self) -> Vec<(Address, u64)> {
+        let mut shares: Vec<(Address, u64)> = self.round.iter().map(|(address, shares)| (*address, *shares)).collect();
+        shares.sort();
+        shares
+    }
+
+    /// Takes the shares a found block paid for out of the round. Shares
+    /// submitted after its job was built stay for the next block.
+    fn settle(&mut self, paid: &[(Address, u64)]) {
+        for (address, shares) in paid {
+            if let Some(round) = self.round.get_mut(address) {
+                *round = round.saturating_sub(*shares);
+            }
+        }
+        self.round.retain(|_, shares| *shares > 0);
+    }
+}
+
+/// A mining pool speaking a line-delimited JSON protocol modeled on
+/// Stratum (see `Request`). Every worker gets the same jobs, built from
+/// the block template with the reward split by the round's shares, but
+/// searches its own extra-nonce range. Solved blocks go through
+/// `Miner::submit_header`. Clones share the same pool.
+///
+/// `state` is a plain mutex that is never held across an await.
+#[derive(Debug, Clone)]
+pub struct StratumServer {
+    blockchain: SharedBlockchain,
+    miner: Miner,
+    config: PoolConfig,
+    state: Arc<Mutex<PoolState>>,
+}
+
+impl StratumServer {
+    pub fn new(blockchain: SharedBlockchain, miner: Miner, config: PoolConfig) -> StratumServer {
+        StratumServer { blockchain, miner, config, state: Arc::new(Mutex::new(PoolState::default())) }
+    }
+
+    /// Starts listening for workers and refreshing jobs in the background.
+    /// Returns the bound listen address, if any.
+    pub async fn start(&self) -> io::Result<Option<SocketAddr>> {
+        let Some(addr) = self.config.listen else {
+            return Ok(None);
+        };
+        let listener = TcpListener::bind(addr).await?;
+        let local = listener.local_addr()?;
+        info!("Listening for pool workers on {}", local);
+        tokio::spawn(self.clone().accept_workers(listener));
+        tokio::spawn(self.clone().drive_jobs());
+        Ok(Some(local))
+    }
+
+    pub fn status(&self) -> PoolStatus {
+        let state = self.state.lock().unwrap();
+        let mut workers: Vec<WorkerInfo> = state
+            .workers
+            .values()
+            .map(|worker| WorkerInfo {
+                name: worker.name.clone(),
+                address: worker.address,
+                nonce_start: worker.nonce_range.start,
+                accepted: worker.accepted,
+                rejected: worker.rejected,
+            })
+            .collect();
+        workers.sort_by_key(|worker| worker.nonce_start);
+        PoolStatus { workers, round_shares: state.round_shares(), blocks_found: state.blocks_found }
+    }
+
+    async fn accept_workers(self, listener: TcpListener) {
+        loop {
+            match listener.accept().await {
+                Ok((stream, addr)) => {
+                    let pool = self.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) = pool.run_connection(stream, addr).await {
+                            debug!("Worker {} disconnected: {}", addr, err);
+                        }
+                    });
+                }
+                Err(err) => warn!("Failed to accept worker: {}", err),
+            }
+        }
+    }
+
+    async fn run_connection(&self, stream: TcpStream, addr: SocketAddr) -> io::Result<()> {
+        let (read, mut write) = stream.into_split();
+        let mut reader = BufReader::new(read);
+        let (sender, mut outgoing) = mpsc::unbounded_channel();
+        let writer = tokio::spawn(async move {
+            while let Some(reply) = outgoing.recv().await {
+                if let Err(err) = write_message(&mut write, &reply).await {
+                    debug!("Failed to write to worker {}: {}", addr, err);
+                    break;
+                }
+            }
+        });
+
+        let mut extranonce = None;
+        let result = loop {
+            let request = match read_message::<_, Request>(&mut reader).await {
+                Ok(Some(request)) => request,
+                Ok(None) => break Ok(()),
+                Err(err) => break Err(err),
+            };
+            let reply = match request {
+                Request::Subscribe { id, worker, address } => match extranonce {
+                    Some(_) => Reply::Rejected { id, reason: ShareError::AlreadySubscribed.to_string() },
+                    None => match self.subscribe(worker, address, sender.clone()) {
+                        Ok((subscribed, range)) => {
+                            extranonce = Some(subscribed);
+                            let _ = sender.send(Reply::Subscribed { id, nonce_start: range.start, nonce_end: range.end });
+                            self.send_current_job(subscribed).await;
+                            continue;
+                        }
+                        Err(err) => Reply::Rejected { id, reason: err.to_string() },
+                    },
+                },
+                Request::Submit { id, job_id, timestamp, nonce } => {
+                    let result = match extranonce {
+                        Some(extranonce) => self.submit_share(extranonce, job_id, timestamp, nonce).await,
+                        None => Err(ShareError::NotSubscribed),
+                    };
+                    match result {
+                        Ok(block) => Reply::Accepted { id, block },
+                        Err(err) => {
+                            debug!("Rejected share from {}: {}", addr, err);
+                            Reply::Rejected { id, reason: err.to_string() }
+                        }
+                    }
+                }
+            };
+            let _ = sender.send(reply);
+        };
+
+        if let Some(extranonce) = extranonce {
+            self.unsubscribe(extranonce);
+        }
+        writer.abort();
+        result
+    }
+
+    /// Registers a worker and returns its extra-nonce and nonce range,
+    /// reusing the extra-nonce of a worker that left when there is one.
+    fn subscribe(&self, name: String, address: Address, sender: mpsc::UnboundedSender<Reply>) -> Result<(u64, Range<u64>), ShareError> {
+        let mut state = self.state.lock().unwrap();
+        let extranonce = match state.free_extranonces.pop_first() {
+            Some(extranonce) => extranonce,
+            None if state.next_extranonce < MAX_EXTRANONCES => {
+                state.next_extranonce += 1;
+                state.next_extranonce - 1
+            }
+            None => return Err(ShareError::PoolFull),
+        };
+        let nonce_range = extranonce << EXTRANONCE_BITS..(extranonce + 1) << EXTRANONCE_BITS;
+        info!("Worker {} joined the pool paying {} with extra-nonce {}", name, address, extranonce);
+        let worker = Worker { name, address, nonce_range: nonce_range.clone(), sender, accepted: 0, rejected: 0 };
+        state.workers.insert(extranonce, worker);
+        Ok((extranonce, nonce_range))
+    }
+
+    /// Forgets a worker that left and frees its extra-nonce.
+    fn unsubscribe(&self, extranonce: u64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(worker) = state.workers.remove(&extranonce) {
+            info!("Worker {} left the pool", worker.name);
+            state.free_extranonces.insert(extranonce);
+        }
+    }
+
+    /// Sends a newly subscribed worker the latest job, building the first
+    /// one if there's none yet.
+    async fn send_current_job(&self, extranonce: u64) {
+        let current = {
+            let state = self.state.lock().unwrap();
+            state.jobs.back().map(|open| Job { clean: true, ..open.job.clone() })
+        };
+        match current {
+            Some(job) => {
+                if let Some(worker) = self.state.lock().unwrap().workers.get(&extranonce) {
+                    let _ = worker.sender.send(Reply::Job(job));
+                }
+            }
+            None => self.refresh_job().await,
+        }
+    }
+
+    /// Builds a job from a fresh block template and sends it to every
+    /// worker. It pays for the round's shares so far, or pays the pool
+    /// address while there are none. It's a clean job when the tip moved
+    /// since the last one.
+    async fn refresh_job(&self) {
+        let Some(pool_address) = self.config.address else {
+            return;
+        };
+        let paid = self.state.lock().unwrap().round_shares();
+        let payouts = match paid.is_empty() {
+            true => vec![(pool_address, 1)],
+            false => paid.clone(),
+        };
+        let template = self.miner.block_template_paying(&payouts).await;
+
+        let mut state = self.state.lock().unwrap();
+        let clean = state.jobs.back().is_none_or(|open| open.job.prev_hash != template.prev_hash);
+        if clean {
+            state.jobs.clear();
+        }
+        if state.jobs.len() >= MAX_JOBS {
+            state.jobs.pop_front();
+        }
+        let job = Job {
+            job_id: state.next_job_id,
+            index: template.index,
+            timestamp: template.timestamp,
+            difficulty: template.difficulty,
+            prev_hash: template.prev_hash,
+            merkle_root: template.merkle_root,
+            header_prefix: template.header_prefix,
+            share_difficulty: self.config.share_difficulty.min(template.difficulty),
+            clean,
+        };
+        debug!("New pool job {} for block {}, paying {:?}", job.job_id, job.index, payouts);
+        state.next_job_id += 1;
+        state.job_created = Some(Instant::now());
+        for worker in state.workers.values() {
+            let _ = worker.sender.send(Reply::Job(job.clone()));
+        }
+        state.jobs.push_back(OpenJob { job, paid, seen: HashSet::new() });
+    }
+
+    /// Checks a share and counts it for the worker. A share that also
+    /// meets the block difficulty is submitted as a block, which settles
+    /// the shares its job paid for. Returns whether the block was added to
+    /// the main chain.
+    async fn submit_share(&self, extranonce: u64, job_id: u64, timestamp: u64, nonce: u64) -> Result<bool, ShareError> {
+        let result = self.check_share(extranonce, job_id, timestamp, nonce);
+        let (header, paid) = {
+            let mut state = self.state.lock().unwrap();
+            let worker = state.workers.get_mut(&extranonce).ok_or(ShareError::NotSubscribed)?;
+            let (header, hash) = result.inspect_err(|_| worker.rejected += 1)?;
+            worker.accepted += 1;
+            let address = worker.address;
+            *state.round.entry(address).or_default() += 1;
+            if !Hashing::meets_difficulty(&hash, header.difficulty) {
+                return Ok(false);
+            }
+            let paid = state.jobs.iter().find(|open| open.job.job_id == job_id).map(|open| open.paid.clone());
+            (header, paid.unwrap_or_default())
+        };
+
+        match self.miner.submit_header(&header).await {
+            Ok(submitted) if submitted.main_chain => {
+                {
+                    let mut state = self.state.lock().unwrap();
+                    state.blocks_found += 1;
+                    state.settle(&paid);
+                }
+                info!("Pool found block {} {}", submitted.index, submitted.hash);
+                self.refresh_job().await;
+                Ok(true)
+            }
+            Ok(submitted) => {
+                info!("Pool block {} {} went on a side branch", submitted.index, submitted.hash);
+                Ok(false)
+            }
+            Err(err) => {
+                warn!("Pool block for job {} was rejected: {}", job_id, err);
+                Ok(false)
+            }
+        }
+    }
+
+    /// Validates a share against its job and the worker's range, and
+    /// returns its header and hash.
+    fn check_share(&self, extranonce: u64, job_id: u64, timestamp: u64, nonce: u64) -> Result<(BlockHeader, BlockID), ShareError> {
+        let mut state = self.state.lock().unwrap();
+        let worker = state.workers.get(&extranonce).ok_or(ShareError::NotSubscribed)?;
+        if !worker.nonce_range.contains(&nonce) {
+            return Err(ShareError::NonceOutOfRange(nonce));
+        }
+        let open = state
+            .jobs
+            .iter_mut()
+            .find(|open| open.job.job_id == job_id)
+            .ok_or(ShareError::UnknownJob(job_id))?;
+        if timestamp < open.job.timestamp || timestamp > unix_time() + MAX_FUTURE_TIMESTAMP {
+            return Err(ShareError::InvalidTimestamp(timestamp));
+        }
+        let header = BlockHeader {
+            index: open.job.index,
+            timestamp,
+            difficulty: open.job.difficulty,
+            prev_hash: open.job.prev_hash,
+            merkle_root: open.job.merkle_root,
+            nonce,
+        };
+        let hash = header.hash();
+        if !Hashing::meets_difficulty(&hash, open.job.share_difficulty) {
+            return Err(ShareError::LowDifficulty(open.job.share_difficulty));
+        }
+        if !open.seen.insert((timestamp, nonce)) {
+            return Err(ShareError::Duplicate);
+        }
+        Ok((header, hash))
+    }
+
+    /// Hands out a new job when the tip moves, and a refreshed one every
+    /// `JOB_REFRESH_INTERVAL` so the coinbase follows the share counts.
+    async fn drive_jobs(self) {
+        loop {
+            tokio::time::sleep(TIP_POLL_INTERVAL).await;
+            let tip = self.tip().await;
+            let due = {
+                let state = self.state.lock().unwrap();
+                !state.workers.is_empty()
+                    && match (state.jobs.back(), state.job_created) {
+                        (Some(open), Some(created)) => open.job.prev_hash != tip || created.elapsed() >= JOB_REFRESH_INTERVAL,
+                        _ => true,
+                    }
+            };
+            if due {
+                self.refresh_job().await;
+            }
+        }
+    }
+
+    async fn tip(&self) -> BlockID {
+        self.blockchain.lock().await.chain.last().map(|block| block.hash).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::block::Block;
+    use crate::blockchain::core::Blockchain;
+    use crate::blockchain::db::core::Store;
+    use crate::blockchain::db::memory::core::MemoryStore;
+    use crate::blockchain::hashing::CancelToken;
+    use crate::blockchain::miner::MinerConfig;
+    use crate::blockchain::p2p::core::{Network, P2pConfig};
+    use crate::blockchain::test_util::mine_block;
+    use crate::blockchain::transaction_pool::TransactionPool;
+    use crate::utils::calculations::calculate_mining_reward;
+    use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+    use tokio::sync::Mutex as AsyncMutex;
+
+    struct TestWorker {
+        reader: BufReader<OwnedReadHalf>,
+        writer: OwnedWriteHalf,
+        job: Option<Job>,
+    }
+
+    impl TestWorker {
+        async fn connect(addr: SocketAddr) -> TestWorker {
+            let (read, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+            TestWorker { reader: BufReader::new(read), writer, job: None }
+        }
+
+        /// Sends `request` and returns its reply, keeping the latest job.
+        async fn call(&mut self, request: Request) -> Reply {
+            write_message(&mut self.writer, &request).await.unwrap();
+            self.next_reply().await
+        }
+
+        async fn next_reply(&mut self) -> Reply {
+            loop {
+                let reply = tokio::time::timeout(Duration::from_secs(5), read_message(&mut self.reader)).await;
+                match reply.expect("no reply in time").unwrap().unwrap() {
+                    Reply::Job(job) => self.job = Some(job),
+                    reply => return reply,
+                }
+            }
+        }
+
+        async fn wait_for_job(&mut self, job_id: u64) -> Job {
+            while self.job.as_ref().is_none_or(|job| job.job_id < job_id) {
+                match tokio::time::timeout(Duration::from_secs(5), read_message(&mut self.reader)).await.unwrap().unwrap().unwrap() {
+                    Reply::Job(job) => self.job = Some(job),
+                    reply => panic!("unexpected {:?}", reply),
+                }
+            }
+            self.job.clone().unwrap()
+        }
+    }
+
+    /// The next nonce from `nonces` whose header is a share but not a block.
+    fn share(job: &Job, nonces: &mut Range<u64>) -> u64 {
+        loop {
+            let nonce = nonces.next().unwrap();
+            let header = BlockHeader {
+                index: job.index,
+                timestamp: job.timestamp,
+                difficulty: job.difficulty,
+                prev_hash: job.prev_hash,
+                merkle_root: job.merkle_root,
+                nonce,
+            };
+            let hash = header.hash();
+            if Hashing::meets_difficulty(&hash, job.share_difficulty) && !Hashing::meets_difficulty(&hash, job.difficulty) {
+                return nonce;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn splits_the_reward_by_shares() {
+        let store: Store = Arc::new(MemoryStore::new());
+        let mut blockchain = Blockchain::new(store.clone()).await.unwrap();
+        let mut transaction_pool = TransactionPool::new(store.clone());
+        // Blocks found this quickly push the difficulty above the share
+        // difficulty, so shares aren't all blocks.
+        for _ in 0..16 {
+            mine_block(&mut blockchain, &mut transaction_pool, &Address::from([3; 20])).await;
+        }
+        let height = blockchain.chain.len();
+        let blockchain = Arc::new(AsyncMutex::new(blockchain));
+        let transaction_pool = Arc::new(AsyncMutex::new(transaction_pool));
+        let network = Network::new(blockchain.clone(), transaction_pool.clone(), store, P2pConfig::default());
+        let miner = Miner::new(blockchain.clone(), transaction_pool, network, MinerConfig::default());
+        let operator = Address::from([9; 20]);
+        let config = PoolConfig { listen: Some("127.0.0.1:0".parse().unwrap()), address: Some(operator), share_difficulty: 2 };
+        let server = StratumServer::new(blockchain.clone(), miner, config);
+        let addr = server.start().await.unwrap().unwrap();
+
+        let (alice, bob) = (Address::from([1; 20]), Address::from([2; 20]));
+        let mut a = TestWorker::connect(addr).await;
+        let mut b = TestWorker::connect(addr).await;
+        let Reply::Subscribed { nonce_start, nonce_end, .. } = a.call(Request::Subscribe { id: 1, worker: "a".into(), address: alice }).await else {
+            panic!("not subscribed");
+        };
+        let mut a_nonces = nonce_start..nonce_end;
+        let Reply::Subscribed { nonce_start, nonce_end, .. } = b.call(Request::Subscribe { id: 1, worker: "b".into(), address: bob }).await else {
+            panic!("not subscribed");
+        };
+        let mut b_nonces = nonce_start..nonce_end;
+        assert!(a_nonces.end <= b_nonces.start);
+
+        let job = a.wait_for_job(0).await;
+        assert_eq!(job.share_difficulty, 2);
+        let mut last = 0;
+        for id in 2..5 {
+            last = share(&job, &mut a_nonces);
+            let submit = Request::Submit { id, job_id: job.job_id, timestamp: job.timestamp, nonce: last };
+            assert_eq!(a.call(submit).await, Reply::Accepted { id, block: false });
+        }
+        let duplicate = Request::Submit { id: 5, job_id: job.job_id, timestamp: job.timestamp, nonce: last };
+        assert_eq!(a.call(duplicate).await, Reply::Rejected { id: 5, reason: ShareError::Duplicate.to_string() });
+        let foreign = Request::Submit { id: 6, job_id: job.job_id, timestamp: job.timestamp, nonce: b_nonces.start };
+        assert!(matches!(a.call(foreign).await, Reply::Rejected { id: 6, .. }));
+
+        let job = b.wait_for_job(job.job_id).await;
+        let nonce = share(&job, &mut b_nonces);
+        let submit = Request::Submit { id: 2, job_id: job.job_id, timestamp: job.timestamp, nonce };
+        assert_eq!(b.call(submit).await, Reply::Accepted { id: 2, block: false });
+        assert_eq!(server.status().round_shares, vec![(alice, 3), (bob, 1)]);
+
+        // A new job pays out by the shares so far. Bob's next share comes
+        // too late for it and is kept for the next block; alice solves it.
+        let next_job_id = server.state.lock().unwrap().next_job_id;
+        server.refresh_job().await;
+        let job = a.wait_for_job(next_job_id).await;
+        let late = b.wait_for_job(next_job_id).await;
+        let nonce = share(&late, &mut b_nonces);
+        let submit = Request::Submit { id: 3, job_id: late.job_id, timestamp: late.timestamp, nonce };
+        assert_eq!(b.call(submit).await, Reply::Accepted { id: 3, block: false });
+        let mut block = Block::new(job.index, String::new(), job.prev_hash);
+        block.timestamp = job.timestamp;
+        block.merkle_root = job.merkle_root;
+        let mined = Hashing::new(block).with_nonce_range(a_nonces).mine_block(job.difficulty, &CancelToken::new(), None).unwrap();
+        let submit = Request::Submit { id: 7, job_id: job.job_id, timestamp: mined.header.timestamp, nonce: mined.header.nonce };
+        assert_eq!(a.call(submit).await, Reply::Accepted { id: 7, block: true });
+
+        let reward = calculate_mining_reward(height as u64, Default::default()).base_units();
+        let blockchain = blockchain.lock().await;
+        assert_eq!(blockchain.chain.len(), height + 1);
+        assert_eq!(blockchain.ledger.balance(&alice).base_units(), reward * 3 / 4);
+        assert_eq!(blockchain.ledger.balance(&bob).base_units(), reward - reward * 3 / 4);
+        assert!(blockchain.ledger.balance(&operator).is_zero());
+        let status = server.status();
+        assert_eq!(status.blocks_found, 1);
+        assert_eq!(status.round_shares, vec![(alice, 1), (bob, 1)]);
+        assert_eq!((status.workers[0].accepted, status.workers[0].rejected), (4, 2));
+    }
+
+    #[tokio::test]
+    async fn pays_the_pool_address_until_there_are_shares() {
+        let store: Store = Arc::new(MemoryStore::new());
+        let blockchain = Arc::new(AsyncMutex::new(Blockchain::new(store.clone()).await.unwrap()));
+        let transaction_pool = Arc::new(AsyncMutex::new(TransactionPool::new(store.clone())));
+        let network = Network::new(blockchain.clone(), transaction_pool.clone(), store, P2pConfig::default());
+        let miner = Miner::new(blockchain.clone(), transaction_pool, network, MinerConfig::default());
+        let operator = Address::from([9; 20]);
+        let config = PoolConfig { listen: None, address: Some(operator), share_difficulty: 1 };
+        let server = StratumServer::new(blockchain.clone(), miner, config);
+        let worker = Address::from([1; 20]);
+        let (sender, _outgoing) = mpsc::unbounded_channel();
+        let (extranonce, nonces) = server.subscribe("first".into(), worker, sender).unwrap();
+
+        // The first share of the round is also a block, built before anyone
+        // had a share to be paid for.
+        server.refresh_job().await;
+        let job = server.state.lock().unwrap().jobs[0].job.clone();
+        let mut block = Block::new(job.index, String::new(), job.prev_hash);
+        block.timestamp = job.timestamp;
+        block.merkle_root = job.merkle_root;
+        let mined = Hashing::new(block).with_nonce_range(nonces).mine_block(job.difficulty, &CancelToken::new(), None).unwrap();
+        assert_eq!(server.submit_share(extranonce, job.job_id, mined.header.timestamp, mined.header.nonce).await, Ok(true));
+
+        let reward = calculate_mining_reward(1, Default::default());
+        let blockchain = blockchain.lock().await;
+        assert_eq!(blockchain.ledger.balance(&operator), reward);
+        assert!(blockchain.ledger.balance(&worker).is_zero());
+        assert_eq!(server.status().round_shares, vec![(worker, 1)]);
+    }
+
+    #[tokio::test]
+    async fn reuses_extranonces_and_refuses_when_full() {
+        let store: Store = Arc::new(MemoryStore::new());
+        let blockchain = Arc::new(AsyncMutex::new(Blockchain::new(store.clone()).await.unwrap()));
+        let transaction_pool = Arc::new(AsyncMutex::new(TransactionPool::new(store.clone())));
+        let network = Network::new(blockchain.clone(), transaction_pool.clone(), store, P2pConfig::default());
+        let miner = Miner::new(blockchain.clone(), transaction_pool, network, MinerConfig::default());
+        let server = StratumServer::new(blockchain, miner, PoolConfig::default());
+        let (sender, _outgoing) = mpsc::unbounded_channel();
+        let subscribe = |name: &str| server.subscribe(name.into(), Address::from([1; 20]), sender.clone());
+
+        assert_eq!(subscribe("a").unwrap().0, 0);
+        assert_eq!(subscribe("b").unwrap().0, 1);
+        server.unsubscribe(0);
+        assert_eq!(subscribe("c").unwrap().0, 0);
+
+        server.state.lock().unwrap().next_extranonce = MAX_EXTRANONCES - 1;
+        let (last, range) = subscribe("d").unwrap();
+        assert_eq!((last, range.end), (MAX_EXTRANONCES - 1, MAX_EXTRANONCES << EXTRANONCE_BITS));
+        assert_eq!(subscribe("e"), Err(ShareError::PoolFull));
+        server.unsubscribe(1);
+        assert_eq!(subscribe("e").unwrap().0, 1);
+    }
+}
@@ -0,0 +1,86 @@
+use std::io;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_with::{hex::Hex, serde_as};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::blockchain::block::BlockID;
+use crate::blockchain::merkle::MerkleHash;
+use crate::blockchain::wallet::Address;
+
+/// Longest line a worker may send.
+pub const MAX_MESSAGE_SIZE: u64 = 64 * 1024;
+
+/// Worker to pool requests, sent as one JSON object per line. The pool
+/// answers each with a `Reply` carrying the same `id`.
+///
+/// A worker starts with `Subscribe` and then gets `Job`s to search. It
+/// hashes a job's `header_prefix` followed by a big-endian 8 byte nonce
+/// from its own range and sends every header that meets the job's share
+/// difficulty with `Submit`. Once its range runs out it may move the
+/// timestamp forward and start over.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "method", rename_all = "snake_case")]
+pub enum Request {
+    /// Joins the pool as `worker`, paying its part of block rewards to
+    /// `address`.
+    Subscribe { id: u64, worker: String, address: Address },
+    Submit { id: u64, job_id: u64, timestamp: u64, nonce: u64 },
+}
+
+/// Work for the next block. Only the timestamp and nonce are the worker's
+/// to change.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Job {
+    pub job_id: u64,
+    pub index: u32,
+    pub timestamp: u64,
+    pub difficulty: u32,
+    pub prev_hash: BlockID,
+    #[serde_as(as = "Hex")]
+    pub merkle_root: MerkleHash,
+    #[serde_as(as = "Hex")]
+    pub header_prefix: Vec<u8>,
+    /// Headers meeting this lower difficulty count as shares.
+    pub share_difficulty: u32,
+    /// Earlier jobs build on an old tip and should be dropped.
+    pub clean: bool,
+}
+
+/// Pool to worker messages.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Reply {
+    /// The worker's extra-nonce range, `nonce_start..nonce_end`, which no
+    /// other worker searches.
+    Subscribed { id: u64, nonce_start: u64, nonce_end: u64 },
+    Job(Job),
+    /// The share counted, and `block` tells if it also solved the block.
+    Accepted { id: u64, block: bool },
+    Rejected { id: u64, reason: String },
+}
+
+/// Reads the next message. Returns `None` once the other side closes the
+/// connection cleanly.
+pub async fn read_message<R: AsyncBufRead + Unpin, T: DeserializeOwned>(reader: &mut R) -> io::Result<Option<T>> {
+    let mut line = String::new();
+    let read = (&mut *reader).take(MAX_MESSAGE_SIZE).read_line(&mut line).await?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with('\n') {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "message is too long or truncated"));
+    }
+    serde_json::from_str(&line)
+        .map(Some)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+pub async fn write_message<W: AsyncWrite + Unpin, T: Serialize>(writer: &mut W, message: &T) -> io::Result<()> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    writer.write_all(&line).await?;
+    writer.flush().await
+}
@@ -0,0 +1,18 @@
+use crate::blockchain::block::Block;
+use crate::blockchain::core::Blockchain;
+use crate::blockchain::hashing::{CancelToken, Hashing};
+use crate::blockchain::transaction_pool::TransactionPool;
+use crate::blockchain::wallet::Address;
+
+/// Mines a block on the tip with the pool's transactions, paying
+/// `miner_address`, and adds it. This searches while the caller holds the
+/// chain, which is fine in tests; the node mines through `Miner`.
+pub async fn mine_block(blockchain: &mut Blockchain, transaction_pool: &mut TransactionPool, miner_address: &Address) -> Block {
+    let mut hasher = Hashing::new(blockchain.block_template(transaction_pool, miner_address));
+    hasher.mine_block(blockchain.difficulty, &CancelToken::new(), None).expect("Mining without a timeout finishes");
+    blockchain
+        .submit_block(hasher.block.clone(), transaction_pool)
+        .await
+        .expect("Mined block failed validation");
+    hasher.block
+}
@@ -1,32 +1,67 @@
 use std::ops::Deref;
+use std::str::FromStr;
 use std::fmt::{self, Display};
 
 use ed25519_dalek::Signer;
 
-use ed25519_dalek::{Signature, SigningKey};
-use serde_with::skip_serializing_none;
+use ed25519_dalek::{Signature, SigningKey, VerifyingKey, PUBLIC_KEY_LENGTH, SIGNATURE_LENGTH};
+use serde_with::{hex::Hex, serde_as, skip_serializing_none};
 use faster_hex::hex_encode;
-use rand::rngs::OsRng;
 use serde::Deserialize;
 use serde::Serialize;
+use sha3::{Digest, Sha3_256};
+use thiserror::Error;
 
+use crate::blockchain::amount::Amount;
+use crate::blockchain::wallet::{Address, ADDRESS_HASH_LENGTH, ADDRESS_PREFIX};
+use crate::utils::calculations::calculate_fee;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum TransactionError {
+    #[error("amount must be greater than zero")]
+    ZeroAmount,
+    #[error("the reward address cannot receive funds")]
+    InvalidReceiver,
+    #[error("fee {found} is below the required {required}")]
+    InsufficientFee { required: Amount, found: Amount },
+    #[error("transaction is not signed")]
+    MissingSignature,
+    #[error("sender does not match the signing public key")]
+    SenderMismatch,
+    #[error("signature verification failed")]
+    BadSignature,
+    #[error("block rewards can only be created by miners")]
+    UnexpectedReward,
+}
+
+
+#[serde_as]
 #[skip_serializing_none]
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Transaction {
-    pub sender: String,
-    pub receiver: String,
-    pub amount: f64,
+    pub sender: Address,
+    pub receiver: Address,
+    pub amount: Amount,
     pub timestamp: u64,
-    pub fee: f64,
+    pub fee: Amount,
+    /// Height of the block a reward transaction pays out, so that otherwise
+    /// identical rewards still get distinct IDs.
+    pub reward_height: Option<u32>,
+    /// Sender's ed25519 public key; `sender` must be derived from it.
+    #[serde_as(as = "Option<Hex>")]
+    pub public_key: Option<[u8; PUBLIC_KEY_LENGTH]>,
+    /// ed25519 signature over `signing_bytes`.
+    #[serde_as(as = "Option<Hex>")]
+    pub signature: Option<[u8; SIGNATURE_LENGTH]>,
 }
 
 impl Transaction {
     pub fn new(
-        sender: String,
-        receiver: String,
-        amount: f64,
+        sender: Address,
+        receiver: Address,
+        amount: Amount,
         timestamp: u64,
-        fee: f64,
+        fee: Amount,
     ) -> Transaction {
         Transaction {
             sender,
@@ -34,35 +69,114 @@ impl Transaction {
             amount,
             timestamp,
             fee,
+            reward_height: None,
+            public_key: None,
+            signature: None,
         }
     }
 
-    pub fn to_string(&self) -> String {
-        format!(
-            "{} transferred {} to {}",
-            self.sender, self.amount, self.receiver
-        )
+    /// A block reward paying `amount` to `receiver` at `height`.
+    pub fn reward(receiver: Address, amount: Amount, fee: Amount, timestamp: u64, height: u32) -> Transaction {
+        Transaction {
+            reward_height: Some(height),
+            ..Transaction::new(Address::reward(), receiver, amount, timestamp, fee)
+        }
+    }
+
+    pub fn is_reward(&self) -> bool {
+        self.sender == Address::reward()
+    }
+
+    /// Checks the transaction fields that don't depend on chain state.
+    pub fn validate(&self) -> Result<(), TransactionError> {
+        if self.amount.is_zero() {
+            return Err(TransactionError::ZeroAmount);
+        }
+        if self.receiver == Address::reward() {
+            return Err(TransactionError::InvalidReceiver);
+        }
+        if self.is_reward() {
+            return Ok(());
+        }
+        let required_fee = calculate_fee(self.amount);
+        if self.fee < required_fee {
+            return Err(TransactionError::InsufficientFee { required: required_fee, found: self.fee });
+        }
+        self.verify_signature()
     }
 
+    /// Checks that the transaction is signed by the key its `sender` names.
+    pub fn verify_signature(&self) -> Result<(), TransactionError> {
+        let (Some(public_key), Some(signature)) = (&self.public_key, &self.signature) else {
+            return Err(TransactionError::MissingSignature);
+        };
+        let verifying_key = VerifyingKey::from_bytes(public_key).map_err(|_| TransactionError::BadSignature)?;
+        if self.sender != Address::from_public_key(&verifying_key) {
+            return Err(TransactionError::SenderMismatch);
+        }
+        verifying_key
+            .verify_strict(&self.signing_bytes(), &Signature::from_bytes(signature))
+            .map_err(|_| TransactionError::BadSignature)
+    }
+
+    /// Sets `sender` and `public_key` from `signing_key` and signs the transaction.
+    pub fn sign(&mut self, signing_key: &SigningKey) {
+        let verifying_key = signing_key.verifying_key();
+        self.sender = Address::from_public_key(&verifying_key);
+        self.public_key = Some(verifying_key.to_bytes());
+        self.signature = Some(signing_key.sign(&self.signing_bytes()).to_bytes());
+    }
+
+    /// Canonical binary encoding of everything except the signature:
+    /// sender and receiver as `ADDRESS_PREFIX` + 20 hash bytes, amount and fee
+    /// as big-endian u64 base units, the big-endian timestamp, then
+    /// `reward_height` (big-endian u32) and `public_key` (32 bytes), each
+    /// behind a presence byte. This is the message that gets signed.
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(2 * (1 + ADDRESS_HASH_LENGTH) + 62);
+        for address in [&self.sender, &self.receiver] {
+            bytes.push(ADDRESS_PREFIX);
+            bytes.extend_from_slice(address.as_bytes());
+        }
+        bytes.extend_from_slice(&self.amount.base_units().to_be_bytes());
+        bytes.extend_from_slice(&self.fee.base_units().to_be_bytes());
+        bytes.extend_from_slice(&self.timestamp.to_be_bytes());
+        push_optional(&mut bytes, self.reward_height.map(u32::to_be_bytes).as_ref());
+        push_optional(&mut bytes, self.public_key.as_ref());
+        bytes
+    }
+
+    /// `signing_bytes` followed by the signature (64 bytes) behind a presence byte.
     pub fn to_bytes(&self) -> Vec<u8> {
-        self.to_string().bytes().collect()
+        let mut bytes = self.signing_bytes();
+        push_optional(&mut bytes, self.signature.as_ref());
+        bytes
     }
 
-    pub fn sign_transaction(&mut self) -> (Vec<u8>, SigningKey, Vec<u8>) {
-        let message = self.to_bytes();
-        let mut csprng = OsRng;
-        let signing_key: SigningKey = SigningKey::generate(&mut csprng);
-        let signature: Signature = signing_key.try_sign(message.as_slice()).unwrap();
-        (signature.to_bytes().to_vec(), signing_key, message)
+    /// SHA3-256 of the canonical encoding.
+    pub fn id(&self) -> TransactionID {
+        TransactionID(Sha3_256::digest(self.to_bytes()).into())
     }
 
-    pub fn verify_transaction(
-        &self,
-        signing_key: SigningKey,
-        signature: &Signature,
-        message: Vec<u8>,
-    ) {
-        assert!(signing_key.verify(message.as_slice(), signature).is_ok())
+}
+
+fn push_optional<const N: usize>(bytes: &mut Vec<u8>, value: Option<&[u8; N]>) {
+    match value {
+        Some(value) => {
+            bytes.push(1);
+            bytes.extend_from_slice(value);
+        }
+        None => bytes.push(0),
+    }
+}
+
+impl Display for Transaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} transferred {} to {}",
+            self.sender, self.amount, self.receiver
+        )
     }
 }
 
@@ -72,6 +186,7 @@ pub const TRANSACTION_ID_LENGTH: usize = 32;
 #[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
 pub struct TransactionID([u8; TRANSACTION_ID_LENGTH]);
 
+#[allow(dead_code)]
 impl TransactionID {
     pub fn new() -> Self {
         Default::default()
@@ -82,6 +197,35 @@ impl TransactionID {
     }
 }
 
+impl FromStr for TransactionID {
+    type Err = hex::FromHexError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut bytes = [0u8; TRANSACTION_ID_LENGTH];
+        hex::decode_to_slice(s, &mut bytes)?;
+        Ok(TransactionID(bytes))
+    }
+}
+
+impl Serialize for TransactionID {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for TransactionID {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl From<[u8; TRANSACTION_ID_LENGTH]> for TransactionID {
+    fn from(bytes: [u8; TRANSACTION_ID_LENGTH]) -> Self {
+        TransactionID(bytes)
+    }
+}
+
 impl AsRef<[u8]> for TransactionID {
     fn as_ref(&self) -> &[u8] {
         &self.0
@@ -103,4 +247,63 @@ impl Display for TransactionID {
         let _ = hex_encode(self, &mut buf);
         write!(f, "{}", String::from_utf8_lossy(&buf))
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(seed: u8) -> Address {
+        Address::from_public_key(&SigningKey::from_bytes(&[seed; 32]).verifying_key())
+    }
+
+    fn signed_transaction(signing_key: &SigningKey) -> Transaction {
+        let mut transaction = Transaction::new(Address::default(), address(2), Amount::from_coins(10), 1_700_000_000, calculate_fee(Amount::from_coins(10)));
+        transaction.sign(signing_key);
+        transaction
+    }
+
+    #[test]
+    fn signed_transaction_verifies() {
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        let transaction = signed_transaction(&signing_key);
+        assert_eq!(transaction.sender, Address::from_public_key(&signing_key.verifying_key()));
+        assert_eq!(transaction.validate(), Ok(()));
+    }
+
+    #[test]
+    fn rejects_unsigned_and_tampered_transactions() {
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+
+        let unsigned = Transaction::new(address(1), address(2), Amount::from_coins(10), 0, calculate_fee(Amount::from_coins(10)));
+        assert_eq!(unsigned.validate(), Err(TransactionError::MissingSignature));
+
+        let mut tampered = signed_transaction(&signing_key);
+        tampered.receiver = address(3);
+        assert_eq!(tampered.validate(), Err(TransactionError::BadSignature));
+
+        let mut impersonated = signed_transaction(&SigningKey::from_bytes(&[8; 32]));
+        impersonated.sender = Address::from_public_key(&signing_key.verifying_key());
+        assert_eq!(impersonated.validate(), Err(TransactionError::SenderMismatch));
+
+        let mut cheap = Transaction::new(Address::default(), address(2), Amount::from_coins(10), 0, Amount::ZERO);
+        cheap.sign(&signing_key);
+        assert!(matches!(cheap.validate(), Err(TransactionError::InsufficientFee { .. })));
+    }
+
+    #[test]
+    fn ids_are_deterministic_and_unique() {
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        let transaction = signed_transaction(&signing_key);
+        assert_eq!(transaction.id(), signed_transaction(&signing_key).id());
+        assert_eq!(transaction.id(), transaction.clone().id());
+        assert_eq!(transaction.id().as_hex().parse::<TransactionID>(), Ok(transaction.id()));
+
+        let mut later = transaction.clone();
+        later.timestamp += 1;
+        let mut unsigned = transaction.clone();
+        unsigned.signature = None;
+        let others = [later, unsigned, signed_transaction(&SigningKey::from_bytes(&[8; 32]))];
+        assert!(others.iter().all(|other| other.id() != transaction.id()));
+        assert_ne!(others[0].id(), others[2].id());
+    }
+}
@@ -1,13 +1,33 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
 use rayon::iter::IntoParallelIterator;
+use tokio::sync::Mutex;
 use serde::Serialize;
-use crate::blockchain::transaction::Transaction;
-use super::db::mongodb::core::MongoDB;
+use thiserror::Error;
+use crate::blockchain::core::{ChainUpdate, LedgerError, LedgerState};
+use crate::blockchain::transaction::{Transaction, TransactionError, TransactionID};
+use super::db::core::{Store, StoreError};
+
+#[derive(Debug, Error)]
+pub enum TransactionPoolError {
+    #[error("transaction {0} is already known")]
+    Duplicate(TransactionID),
+    #[error("invalid transaction: {0}")]
+    Invalid(#[from] TransactionError),
+    #[error("transaction can't be applied: {0}")]
+    Ledger(#[from] LedgerError),
+    #[error("database error: {0}")]
+    Database(#[from] StoreError),
+}
+
+pub type SharedTransactionPool = Arc<Mutex<TransactionPool>>;
 
 #[derive(Debug, Clone, Serialize)]
 pub struct TransactionPool {
     pub pool: Vec<Transaction>,
     #[serde(skip)]
-    pub db: MongoDB
+    pub db: Store
 }
 
 impl IntoParallelIterator for TransactionPool {
@@ -22,19 +42,175 @@ impl IntoParallelIterator for TransactionPool {
 }
 
 impl TransactionPool {
-    pub fn new(db: MongoDB) -> TransactionPool {
+    pub fn new(db: Store) -> TransactionPool {
         TransactionPool {
             pool: vec![],
             db,
         }
     }
 
-    pub async fn add_transaction(&mut self, transaction: Transaction) {
-        let _ = self.db.insert_transaction(&transaction).await;
+    /// Rebuilds the pool after a restart from the stored transactions that
+    /// aren't on the chain, oldest first, keeping those that still apply on
+    /// top of `ledger`.
+    pub async fn load(db: Store, ledger: &LedgerState) -> Result<TransactionPool, StoreError> {
+        let mut stored: Vec<Transaction> = db
+            .get_transactions()
+            .await?
+            .into_iter()
+            .filter(|transaction| !transaction.is_reward() && !ledger.is_confirmed(&transaction.id()))
+            .collect();
+        stored.sort_by_key(|transaction| transaction.timestamp);
+
+        let mut pool = TransactionPool::new(db);
+        let mut pending = ledger.clone();
+        pool.pool = stored
+            .into_iter()
+            .filter(|transaction| pending.apply_transaction(transaction).is_ok())
+            .collect();
+        Ok(pool)
+    }
+
+    /// Validates and stores the transaction, then queues it for the next block.
+    /// The sender must be able to pay for it on top of everything they
+    /// already have pending in the pool. Only the pool and the chain count
+    /// as duplicates, so a stored transaction that left the pool can be
+    /// submitted again.
+    pub async fn add_transaction(&mut self, transaction: Transaction, ledger: &LedgerState) -> Result<TransactionID, TransactionPoolError> {
+        if transaction.is_reward() {
+            return Err(TransactionError::UnexpectedReward.into());
+        }
+        transaction.validate()?;
+
+        let id = transaction.id();
+        if self.get(&id).is_some() || ledger.is_confirmed(&id) {
+            return Err(TransactionPoolError::Duplicate(id));
+        }
+
+        let mut pending = ledger.clone();
+        for queued in self.pool.iter().filter(|queued| queued.sender == transaction.sender) {
+            pending.apply_transaction(queued)?;
+        }
+        pending.apply_transaction(&transaction)?;
+
+        match self.db.insert_transaction(&transaction).await {
+            Ok(()) | Err(StoreError::Duplicate(_)) => {}
+            Err(err) => return Err(err.into()),
+        }
         self.pool.push(transaction);
+        Ok(id)
+    }
+
+    pub fn get(&self, id: &TransactionID) -> Option<&Transaction> {
+        self.pool.iter().find(|transaction| transaction.id() == *id)
+    }
+
+    /// Brings the pool in line with a changed main chain: drops what the
+    /// connected blocks included and returns the transactions of
+    /// disconnected blocks, then keeps only what still applies on top of
+    /// `ledger`, oldest first. Returned transactions are already stored, so
+    /// they skip `add_transaction`.
+    pub fn apply_update(&mut self, update: &ChainUpdate, ledger: &LedgerState) {
+        let included: HashSet<TransactionID> =
+            update.connected.iter().flat_map(|block| block.transaction_ids()).collect();
+        let returned = update
+            .disconnected
+            .iter()
+            .flat_map(|block| block.transactions.iter())
+            .filter(|transaction| !transaction.is_reward())
+            .cloned();
+        let candidates: Vec<Transaction> = returned.chain(self.pool.drain(..)).collect();
+
+        let mut pending = ledger.clone();
+        let mut seen = HashSet::new();
+        for transaction in candidates {
+            let id = transaction.id();
+            if included.contains(&id) || !seen.insert(id) {
+                continue;
+            }
+            if pending.apply_transaction(&transaction).is_ok() {
+                self.pool.push(transaction);
+            }
+        }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::amount::Amount;
+    use crate::blockchain::block::{Block, BlockID};
+    use crate::blockchain::core::Blockchain;
+    use crate::blockchain::test_util::mine_block;
+    use crate::blockchain::db::memory::core::MemoryStore;
+    use crate::blockchain::wallet::Address;
+    use crate::utils::calculations::{calculate_fee, calculate_mining_reward};
+    use ed25519_dalek::SigningKey;
+
+    #[tokio::test]
+    async fn rejects_transactions_it_already_has() {
+        let db: Store = Arc::new(MemoryStore::new());
+        let mut pool = TransactionPool::new(db.clone());
+        let signing_key = SigningKey::from_bytes(&[5; 32]);
+        let sender = Address::from_public_key(&signing_key.verifying_key());
+        let mut funding = Block::new(1, String::new(), BlockID::default());
+        let reward = calculate_mining_reward(1, Amount::ZERO);
+        funding.set_transactions(vec![Transaction::reward(sender, reward, Amount::ZERO, 0, 1)]);
+        let mut ledger = LedgerState::default();
+        ledger.apply_block(&funding).unwrap();
+
+        let amount = Amount::from_base_units(reward.base_units() / 2);
+        let mut transaction = Transaction::new(sender, Address::from([6; 20]), amount, 1_724_000_000, calculate_fee(amount));
+        transaction.sign(&signing_key);
+        let id = pool.add_transaction(transaction.clone(), &ledger).await.unwrap();
+        assert_eq!(id, transaction.id());
+        assert!(matches!(
+            pool.add_transaction(transaction.clone(), &ledger).await,
+            Err(TransactionPoolError::Duplicate(duplicate)) if duplicate == id
+        ));
+
+        // Dropped from the pool but only stored, it can be submitted again.
+        pool.pool.clear();
+        assert_eq!(pool.add_transaction(transaction.clone(), &ledger).await.unwrap(), id);
+        assert_eq!(db.get_transactions().await.unwrap().len(), 1);
+
+        // Once mined it's on the chain, and still known.
+        pool.pool.clear();
+        let mut block = Block::new(2, String::new(), BlockID::default());
+        block.set_transactions(vec![transaction.clone()]);
+        ledger.apply_block(&block).unwrap();
+        assert!(matches!(
+            pool.add_transaction(transaction, &ledger).await,
+            Err(TransactionPoolError::Duplicate(duplicate)) if duplicate == id
+        ));
+    }
+
+    #[tokio::test]
+    async fn reloads_unconfirmed_transactions_after_a_restart() {
+        let db: Store = Arc::new(MemoryStore::new());
+        let mut blockchain = Blockchain::new(db.clone()).await.unwrap();
+        let mut pool = TransactionPool::new(db.clone());
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        let sender = Address::from_public_key(&signing_key.verifying_key());
+        mine_block(&mut blockchain, &mut pool, &sender).await;
+
+        let amount = Amount::from_base_units(blockchain.ledger.balance(&sender).base_units() / 4);
+        let mut mined = Transaction::new(sender, Address::from([8; 20]), amount, 1_724_000_000, calculate_fee(amount));
+        mined.sign(&signing_key);
+        pool.add_transaction(mined.clone(), &blockchain.ledger).await.unwrap();
+        mine_block(&mut blockchain, &mut pool, &Address::from([9; 20])).await;
+        let mut pending = Transaction::new(sender, Address::from([8; 20]), amount, 1_724_000_001, calculate_fee(amount));
+        pending.sign(&signing_key);
+        pool.add_transaction(pending.clone(), &blockchain.ledger).await.unwrap();
+
+        let blockchain = Blockchain::new(db.clone()).await.unwrap();
+        let mut reloaded = TransactionPool::load(db, &blockchain.ledger).await.unwrap();
+        assert_eq!(reloaded.pool, vec![pending.clone()]);
 
-    pub fn clear_pool(&mut self) {
-        self.pool.clear();
+        reloaded.pool.clear();
+        assert_eq!(reloaded.add_transaction(pending.clone(), &blockchain.ledger).await.unwrap(), pending.id());
+        assert!(matches!(
+            reloaded.add_transaction(mined.clone(), &blockchain.ledger).await,
+            Err(TransactionPoolError::Duplicate(duplicate)) if duplicate == mined.id()
+        ));
     }
 }
@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+
+use crate::blockchain::block::{Block, BlockID};
+
+/// Work a block proves: the expected number of hashes needed to meet its
+/// difficulty. The target is `u64::MAX >> difficulty`, so that's
+/// `2^difficulty`.
+pub fn block_work(difficulty: u32) -> u128 {
+    1u128 << difficulty.min(64)
+}
+
+/// Every valid block we know of with the cumulative work of the chain
+/// ending at it, so the heaviest branch can be picked.
+///
+/// Main chain blocks live in `Blockchain::chain`; only side branch blocks
+/// are kept here. They are in memory only and forgotten on restart.
+#[derive(Debug, Clone, Default)]
+pub struct BlockTree {
+    work: HashMap<BlockID, u128>,
+    side: HashMap<BlockID, Block>,
+}
+
+impl BlockTree {
+    pub fn from_chain(chain: &[Block]) -> BlockTree {
+        let mut tree = BlockTree::default();
+        let mut total: u128 = 0;
+        for block in chain {
+            total = total.saturating_add(block_work(block.difficulty));
+            tree.work.insert(block.hash, total);
+        }
+        tree
+    }
+
+    pub fn contains(&self, hash: &BlockID) -> bool {
+        self.work.contains_key(hash)
+    }
+
+    /// Cumulative work of the chain ending at `hash`.
+    pub fn work(&self, hash: &BlockID) -> Option<u128> {
+        self.work.get(hash).copied()
+    }
+
+    pub fn side_block(&self, hash: &BlockID) -> Option<&Block> {
+        self.side.get(hash)
+    }
+
+    /// Records `block`, whose parent must already be known, and returns its
+    /// cumulative work. `difficulty` is what validation required of it
+    /// rather than what it claims. Blocks not on the main chain are kept as
+    /// side blocks.
+    pub fn insert(&mut self, block: &Block, difficulty: u32, on_main_chain: bool) -> u128 {
+        let parent_work = self.work(&block.prev_hash).unwrap_or_default();
+        let work = parent_work.saturating_add(block_work(difficulty));
+        self.work.insert(block.hash, work);
+        if !on_main_chain {
+            self.side.insert(block.hash, block.clone());
+        }
+        work
+    }
+
+    /// Moves blocks between the main chain and the side branches after a
+    /// reorganization.
+    pub fn reorganize(&mut self, disconnected: &[Block], connected: &[Block]) {
+        for block in connected {
+            self.side.remove(&block.hash);
+        }
+        for block in disconnected {
+            self.side.insert(block.hash, block.clone());
+        }
+    }
+
+    /// Forgets a side block that turned out to be invalid, and every side
+    /// block built on it.
+    pub fn remove_branch(&mut self, hash: &BlockID) {
+        let mut removed = vec![*hash];
+        while let Some(hash) = removed.pop() {
+            self.side.remove(&hash);
+            self.work.remove(&hash);
+            removed.extend(self.side.values().filter(|block| block.prev_hash == hash).map(|block| block.hash));
+        }
+    }
+}
@@ -1,16 +1,111 @@
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+use thiserror::Error;
+
+use crate::blockchain::amount::Amount;
 use crate::blockchain::transaction::Transaction;
 use crate::utils::calculations::calculate_fee;
-use crate::blockchain::db::mongodb::core::MongoDB;
+use crate::blockchain::db::core::Store;
+
+/// Version byte prepended to every address before base58check encoding.
+/// Serenity addresses therefore always start with `S`.
+pub const ADDRESS_PREFIX: u8 = 0x3f;
+
+/// Number of public key hash bytes kept in an address.
+pub const ADDRESS_HASH_LENGTH: usize = 20;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum AddressError {
+    #[error("invalid base58check encoding: {0}")]
+    Encoding(String),
+    #[error("unknown address prefix {0:#04x}")]
+    Prefix(u8),
+    #[error("address must contain {ADDRESS_HASH_LENGTH} hash bytes, got {0}")]
+    Length(usize),
+}
+
+/// A wallet address: the first 20 bytes of the SHA3-256 of an ed25519
+/// public key, written as base58check with `ADDRESS_PREFIX` as the version
+/// byte so a mistyped address fails its checksum.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq, PartialOrd, Ord)]
+pub struct Address([u8; ADDRESS_HASH_LENGTH]);
+
+impl Address {
+    pub fn from_public_key(public_key: &VerifyingKey) -> Address {
+        let hash = Sha3_256::digest(public_key.as_bytes());
+        Address(hash[..ADDRESS_HASH_LENGTH].try_into().unwrap())
+    }
+
+    /// The all-zero address, used as the sender of block rewards. No known
+    /// public key hashes to it.
+    pub fn reward() -> Address {
+        Address::default()
+    }
+
+    pub fn as_bytes(&self) -> &[u8; ADDRESS_HASH_LENGTH] {
+        &self.0
+    }
+}
+
+impl From<[u8; ADDRESS_HASH_LENGTH]> for Address {
+    fn from(hash: [u8; ADDRESS_HASH_LENGTH]) -> Self {
+        Address(hash)
+    }
+}
+
+impl Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let encoded = bs58::encode(self.0).with_check_version(ADDRESS_PREFIX).into_string();
+        write!(f, "{}", encoded)
+    }
+}
+
+impl FromStr for Address {
+    type Err = AddressError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let decoded = bs58::decode(s)
+            .with_check(None)
+            .into_vec()
+            .map_err(|err| AddressError::Encoding(err.to_string()))?;
+        let (prefix, hash) = decoded.split_first().ok_or(AddressError::Length(0))?;
+        if *prefix != ADDRESS_PREFIX {
+            return Err(AddressError::Prefix(*prefix));
+        }
+        let hash = hash.try_into().map_err(|_| AddressError::Length(hash.len()))?;
+        Ok(Address(hash))
+    }
+}
 
+impl Serialize for Address {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Address {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[allow(dead_code)]
 pub struct Wallet {
-    pub address: String,
-    pub balance: f64,
-    pub db: MongoDB,
+    pub address: Address,
+    pub balance: Amount,
+    pub db: Store,
 }
 
+#[allow(dead_code)]
 impl Wallet {
-    pub async fn new(address: String, db: MongoDB) -> Wallet {
-        let balance = db.get_balance(&address).await.unwrap_or(0.0);
+    /// Loads the wallet with its balance from the store's balance cache.
+    pub async fn new(address: Address, db: Store) -> Wallet {
+        let balance = db.get_balance(&address).await.unwrap_or_default();
 
         Wallet {
             address,
@@ -19,41 +114,51 @@ impl Wallet {
         }
     }
 
-    pub async fn send_money(&mut self, receiver: String, amount: f64) -> Transaction {
+    /// Builds and signs a transfer from this wallet. The transaction only
+    /// moves funds once it is mined into a block.
+    pub fn send_money(&self, signing_key: &SigningKey, receiver: Address, amount: Amount) -> Transaction {
         let fee = calculate_fee(amount);
-        let total_amount = amount + fee;
+        let total_amount = amount.checked_add(fee).expect("Amount overflow");
 
         if self.balance < total_amount {
             panic!("Insufficient balance");
         }
 
         let timestamp = chrono::Utc::now().timestamp() as u64;
-        let transaction = Transaction {
-            sender: self.address.clone(),
-            receiver: receiver.clone(),
-            amount,
-            fee,
-            timestamp,
-        };
-
-        self.balance -= total_amount;
-        let _ = self.db.update_balance(&self.address, self.balance).await;
-        let _ = self.db.insert_transaction(&transaction).await;
-
-        let mut receiver_balance = self.db.get_balance(&receiver).await.unwrap_or(0.0);
-        receiver_balance += amount;
-        let _ = self.db.update_balance(&receiver, receiver_balance).await;
-
+        let mut transaction = Transaction::new(self.address, receiver, amount, timestamp, fee);
+        transaction.sign(signing_key);
+        assert_eq!(transaction.sender, self.address, "Signing key does not belong to this wallet");
         transaction
     }
 
-    pub async fn receive_money(&mut self, amount: f64) {
-        self.balance += amount;
+    pub fn get_balance(&self) -> Amount {
+        self.balance
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        let _ = self.db.update_balance(&self.address, self.balance).await;
+    #[test]
+    fn address_round_trip() {
+        let public_key = SigningKey::from_bytes(&[7; 32]).verifying_key();
+        let address = Address::from_public_key(&public_key);
+        let encoded = address.to_string();
+        assert!(encoded.starts_with('S'), "{}", encoded);
+        assert_eq!(encoded.parse::<Address>(), Ok(address));
+        assert!("SaVQRri1UUeBkKQJPRkAM5MLsgSDDibWbd".parse::<Address>().is_ok());
     }
 
-    pub fn get_balance(&self) -> f64 {
-        self.balance
+    #[test]
+    fn rejects_mistyped_addresses() {
+        let address = Address::from_public_key(&SigningKey::from_bytes(&[7; 32]).verifying_key()).to_string();
+        let last = address.chars().last().unwrap();
+        let typo = format!("{}{}", &address[..address.len() - 1], if last == 'a' { 'b' } else { 'a' });
+        assert!(matches!(typo.parse::<Address>(), Err(AddressError::Encoding(_))));
+        assert!("blah".parse::<Address>().is_err());
+
+        let other_network = bs58::encode([0u8; ADDRESS_HASH_LENGTH]).with_check_version(0x00).into_string();
+        assert_eq!(other_network.parse::<Address>(), Err(AddressError::Prefix(0x00)));
     }
 }
@@ -1,66 +1,226 @@
 #![allow(unused)]
-use log::debug;
+use log::{debug, error};
+use ed25519_dalek::{PUBLIC_KEY_LENGTH, SIGNATURE_LENGTH};
 use rocket::serde::{json::Json, Deserialize, Serialize};
+use serde_with::{hex::Hex, serde_as};
 use std::sync::Arc;
+use std::time::Instant;
 use tokio::sync::Mutex;
 
-use crate::blockchain::core::Blockchain;
+use crate::blockchain::amount::Amount;
+use crate::blockchain::core::{Blockchain, ChainError, ReindexReport, SharedBlockchain};
 use rocket::fs::{FileServer, relative, NamedFile};
 use rocket::http::uri::fmt::Kind::Path;
-use rocket::response::content::RawHtml;
-use crate::blockchain::db::mongodb::core::MongoDB;
-use crate::blockchain::transaction::Transaction;
-use crate::blockchain::transaction_pool::TransactionPool;
-use crate::utils::calculations::calculate_fee;
-use crate::blockchain::wallet::Wallet;
-use crate::blockchain::db::mongodb;
-
-type SharedBlockchain = Arc<Mutex<Blockchain>>;
-type SharedTransactionPool = Arc<Mutex<TransactionPool>>;
-type SharedDatabase = Arc<Mutex<MongoDB>>;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::response::{content::RawHtml, status};
+use crate::blockchain::db::core::{open_store, Store};
+use crate::blockchain::hashing::{BlockHeader, CancelToken};
+use crate::blockchain::merkle::MerkleProof;
+use crate::blockchain::miner::{BlockTemplate, Miner, MinerConfig, MinerError, MinerStatus, SubmitError, SubmittedBlock};
+use crate::blockchain::p2p::core::{Network, P2pConfig};
+use crate::blockchain::p2p::peers::PeerList;
+use crate::blockchain::stratum::core::{PoolConfig, PoolStatus, StratumServer};
+use crate::blockchain::transaction::{Transaction, TransactionID};
+use crate::blockchain::transaction_pool::{SharedTransactionPool, TransactionPool, TransactionPoolError};
+use crate::blockchain::wallet::{Address, Wallet};
 
+
+/// A transaction signed by the client. `signature` must cover
+/// `Transaction::signing_bytes` of the resulting transaction.
+#[serde_as]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct TransactionRequest {
-    sender: String,
-    receiver: String,
-    amount: f64,
+    sender: Address,
+    receiver: Address,
+    amount: Amount,
+    fee: Amount,
+    timestamp: u64,
+    #[serde_as(as = "Hex")]
+    public_key: [u8; PUBLIC_KEY_LENGTH],
+    #[serde_as(as = "Hex")]
+    signature: [u8; SIGNATURE_LENGTH],
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct MinerRequest {
-    address: String,
+    address: Address,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct MinerStartRequest {
+    address: Option<Address>,
+    threads: Option<usize>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct WalletRequest {
-    address: String,
+    address: Address,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct MerkleProofResponse {
+    index: u32,
+    merkle_root: String,
+    proof: MerkleProof,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct TransactionResponse {
+    id: TransactionID,
+    transaction: Transaction,
+}
+
+/// Guards admin routes. Requests must send the `SERENITY_ADMIN_TOKEN`
+/// environment value in an `X-Admin-Token` header; without that variable
+/// admin routes are disabled.
+struct AdminToken;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AdminToken {
+    type Error = &'static str;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let Ok(expected) = std::env::var("SERENITY_ADMIN_TOKEN") else {
+            return Outcome::Error((Status::Forbidden, "admin routes are disabled"));
+        };
+        match request.headers().get_one("X-Admin-Token") {
+            Some(token) if !expected.is_empty() && token == expected => Outcome::Success(AdminToken),
+            _ => Outcome::Error((Status::Unauthorized, "missing or wrong X-Admin-Token")),
+        }
+    }
 }
 
 #[post("/transaction", format = "application/json", data = "<transaction>")]
 async fn transaction(
     transaction: Json<TransactionRequest>, 
-    _blockchain: &rocket::State<SharedBlockchain>, 
-    pool: &rocket::State<SharedTransactionPool>
-) -> &'static str {
-    let timestamp = chrono::Utc::now().timestamp() as u64;
-    let tx = Transaction::new(transaction.sender.clone(), transaction.receiver.clone(), transaction.amount, timestamp, calculate_fee(transaction.amount));
-    pool.lock().await.add_transaction(tx.clone()).await;
-    debug!("Transaction added to pool: {:?}", tx);
-    "Transaction received"
-}
-
-#[post("/mine", format = "application/json", data = "<miner>")]
-async fn mine(miner: Json<MinerRequest>, blockchain: &rocket::State<SharedBlockchain>, pool: &rocket::State<SharedTransactionPool>) -> String {
-    let mut blockchain = blockchain.lock().await;
-    let mut pool = pool.lock().await;
-    let (duration, difficulty) = blockchain.mine_block(&mut pool, &miner.address).await;
-    "Block mined in ".to_owned() + duration.as_secs_f64().to_string().as_str() + " seconds" + " with difficulty " + difficulty.to_string().as_str()
+    blockchain: &rocket::State<SharedBlockchain>, 
+    pool: &rocket::State<SharedTransactionPool>,
+    network: &rocket::State<Network>,
+) -> Result<Json<TransactionResponse>, status::Custom<String>> {
+    let mut tx = Transaction::new(transaction.sender, transaction.receiver, transaction.amount, transaction.timestamp, transaction.fee);
+    tx.public_key = Some(transaction.public_key);
+    tx.signature = Some(transaction.signature);
+    tx.verify_signature().map_err(|err| status::Custom(Status::Unauthorized, err.to_string()))?;
+
+    let blockchain = blockchain.lock().await;
+    let id = pool.lock().await.add_transaction(tx.clone(), &blockchain.ledger).await.map_err(|err| {
+        let status = match err {
+            TransactionPoolError::Duplicate(_) => Status::Conflict,
+            TransactionPoolError::Invalid(_) | TransactionPoolError::Ledger(_) => Status::BadRequest,
+            TransactionPoolError::Database(_) => Status::InternalServerError,
+        };
+        status::Custom(status, err.to_string())
+    })?;
+    drop(blockchain);
+    network.announce_transaction(id).await;
+    debug!("Transaction {} added to pool: {:?}", id, tx);
+    Ok(Json(TransactionResponse { id, transaction: tx }))
+}
+
+#[get("/transaction/<tx_id>")]
+async fn get_transaction(
+    tx_id: &str,
+    pool: &rocket::State<SharedTransactionPool>,
+    db: &rocket::State<Store>,
+) -> Option<Json<TransactionResponse>> {
+    let id = tx_id.parse::<TransactionID>().ok()?;
+    if let Some(tx) = pool.lock().await.get(&id) {
+        return Some(Json(TransactionResponse { id, transaction: tx.clone() }));
+    }
+    let tx = db.get_transaction(&id).await.ok()??;
+    Some(Json(TransactionResponse { id, transaction: tx }))
+}
+
+/// Mines one block paying the requested address. The chain and pool are
+/// only locked to build and add the block, not during the search.
+#[post("/mine", format = "application/json", data = "<request>")]
+async fn mine(
+    request: Json<MinerRequest>,
+    blockchain: &rocket::State<SharedBlockchain>,
+    miner: &rocket::State<Miner>,
+    shutdown: &rocket::State<CancelToken>,
+) -> Result<String, status::Custom<String>> {
+    let start = Instant::now();
+    miner
+        .mine_one(&request.address, shutdown)
+        .await
+        .map_err(|err| status::Custom(Status::ServiceUnavailable, err.to_string()))?;
+    let duration = start.elapsed();
+    let difficulty = blockchain.lock().await.difficulty;
+    Ok("Block mined in ".to_owned() + duration.as_secs_f64().to_string().as_str() + " seconds" + " with difficulty " + difficulty.to_string().as_str())
+}
+
+/// Starts the background miner. Both fields fall back to
+/// `SERENITY_MINER_ADDRESS` and `SERENITY_MINER_THREADS`.
+#[post("/miner/start", format = "application/json", data = "<request>")]
+async fn start_miner(
+    _admin: AdminToken,
+    request: Json<MinerStartRequest>,
+    miner: &rocket::State<Miner>,
+) -> Result<Json<MinerStatus>, status::Custom<String>> {
+    miner.start(request.address, request.threads).map(Json).map_err(|err| {
+        let status = match err {
+            MinerError::NoAddress => Status::BadRequest,
+            MinerError::AlreadyRunning => Status::Conflict,
+            MinerError::Threads(_) => Status::InternalServerError,
+        };
+        status::Custom(status, err.to_string())
+    })
+}
+
+#[post("/miner/stop")]
+async fn stop_miner(_admin: AdminToken, miner: &rocket::State<Miner>) -> Json<MinerStatus> {
+    Json(miner.stop())
+}
+
+/// The next block paying the requested address, for mining elsewhere.
+#[post("/getblocktemplate", format = "application/json", data = "<request>")]
+async fn get_block_template(request: Json<MinerRequest>, miner: &rocket::State<Miner>) -> Json<BlockTemplate> {
+    Json(miner.block_template(&request.address).await)
+}
+
+/// Takes a solved header for a template from `/getblocktemplate`.
+#[post("/submitblock", format = "application/json", data = "<header>")]
+async fn submit_block(
+    header: Json<BlockHeader>,
+    miner: &rocket::State<Miner>,
+) -> Result<Json<SubmittedBlock>, status::Custom<String>> {
+    miner.submit_header(&header).await.map(Json).map_err(|err| {
+        let status = match err {
+            SubmitError::UnknownTemplate(_) => Status::NotFound,
+            SubmitError::Rejected(ChainError::Duplicate { .. }) => Status::Conflict,
+            SubmitError::HeaderMismatch { .. } | SubmitError::Rejected(_) => Status::BadRequest,
+        };
+        status::Custom(status, err.to_string())
+    })
+}
+
+#[get("/miner/status")]
+async fn miner_status(miner: &rocket::State<Miner>) -> Json<MinerStatus> {
+    Json(miner.status())
 }
 
 #[get("/wallet/balance", format = "application/json", data = "<wallet>")]
-async fn get_balance(wallet: Json<WalletRequest>, db: &rocket::State<MongoDB>) -> String {
-    let wallet = Wallet::new(wallet.address.clone(), db.inner().clone()).await;
-    format!("Balance: {}", wallet.get_balance())
+async fn get_balance(wallet: Json<WalletRequest>, blockchain: &rocket::State<SharedBlockchain>) -> String {
+    let balance = blockchain.lock().await.ledger.balance(&wallet.address);
+    format!("Balance: {}", balance)
+}
+
+/// Rebuilds stored transactions and balances from the blocks. Pending pool
+/// transactions are stored again afterwards so lookups still find them.
+#[post("/admin/reindex")]
+async fn reindex(
+    _admin: AdminToken,
+    blockchain: &rocket::State<SharedBlockchain>,
+    pool: &rocket::State<SharedTransactionPool>,
+) -> Result<Json<ReindexReport>, status::Custom<String>> {
+    let mut blockchain = blockchain.lock().await;
+    let pool = pool.lock().await;
+    let internal_error = |err: String| status::Custom(Status::InternalServerError, err);
+    let report = blockchain.reindex().await.map_err(|err| internal_error(err.to_string()))?;
+    blockchain.db.index_transactions(&pool.pool).await.map_err(|err| internal_error(err.to_string()))?;
+    Ok(Json(report))
 }
 
 #[get("/blockchain")]
@@ -70,6 +230,15 @@ async fn get_blockchain(blockchain: &rocket::State<SharedBlockchain>) -> Json<Bl
     Json(blockchain.clone())
 }
 
+#[get("/transaction/<tx_id>/proof")]
+async fn get_merkle_proof(tx_id: &str, blockchain: &rocket::State<SharedBlockchain>) -> Option<Json<MerkleProofResponse>> {
+    let tx_id = tx_id.parse::<TransactionID>().ok()?;
+    let blockchain = blockchain.lock().await;
+    let (index, proof) = blockchain.merkle_proof(&tx_id)?;
+    let merkle_root = hex::encode(blockchain.chain[index as usize].merkle_root);
+    Some(Json(MerkleProofResponse { index, merkle_root, proof }))
+}
+
 #[get("/transactions")]
 async fn get_transactions(pool: &rocket::State<SharedTransactionPool>) -> Json<TransactionPool> {
     let pool = pool.lock().await;
@@ -77,6 +246,16 @@ async fn get_transactions(pool: &rocket::State<SharedTransactionPool>) -> Json<T
     Json(pool.clone())
 }
 
+#[get("/peers")]
+async fn get_peers(network: &rocket::State<Network>) -> Json<PeerList> {
+    Json(network.peer_list().await)
+}
+
+#[get("/pool")]
+async fn get_pool(pool: &rocket::State<StratumServer>) -> Json<PoolStatus> {
+    Json(pool.status())
+}
+
 #[get("/")]
 async fn index() -> RawHtml<&'static str> {
     RawHtml(
@@ -142,28 +321,115 @@ async fn index() -> RawHtml<&'static str> {
     )
 }
 
-#[allow(dead_code, unused_variables)]
 #[launch]
 pub async fn rocket() -> _ {
-    let db = mongodb::core::MongoDB::new().await;
-
-    let mut blockchain = Blockchain::new(db.clone()).await;
-    if blockchain.chain.is_empty() {
-        let genesis_block = blockchain.create_genesis_block().await;
-        blockchain.chain.push(genesis_block);
-    }
-
-    let blockchain_state = Arc::new(Mutex::new(blockchain));
-    let transaction_pool = Arc::new(Mutex::new(TransactionPool::new(db.clone())));
+    let db = open_store().await.expect("Failed to open the store");
+    let shutdown = CancelToken::new();
 
+    // Ignite fairings run in order: the schema is migrated before the chain
+    // is loaded from it.
     rocket::build()
-        .manage(blockchain_state)
-        .manage(transaction_pool)
-        .attach(rocket::fairing::AdHoc::on_ignite("Database Migrations", |rocket| async {
-            let db = mongodb::core::MongoDB::new().await;
-            db.migrate().await;
-            rocket
+        .attach(rocket::fairing::AdHoc::try_on_ignite("Database Migrations", {
+            let db = db.clone();
+            |rocket| async move {
+                match db.migrate().await {
+                    Ok(()) => Ok(rocket),
+                    Err(err) => {
+                        error!("Database migration failed: {}", err);
+                        Err(rocket)
+                    }
+                }
+            }
+        }))
+        .attach(rocket::fairing::AdHoc::try_on_ignite("Blockchain", {
+            let db = db.clone();
+            |rocket| async move {
+                let blockchain = match Blockchain::new(db.clone()).await {
+                    Ok(blockchain) => blockchain,
+                    Err(ChainError::Store(err)) => {
+                        error!("Failed to load the blockchain from the store: {}", err);
+                        return Err(rocket);
+                    }
+                    Err(err) => {
+                        error!("Stored blockchain failed validation: {}; wipe the store to start a new chain", err);
+                        return Err(rocket);
+                    }
+                };
+                let transaction_pool = match TransactionPool::load(db.clone(), &blockchain.ledger).await {
+                    Ok(transaction_pool) => transaction_pool,
+                    Err(err) => {
+                        error!("Failed to load the pending transactions: {}", err);
+                        return Err(rocket);
+                    }
+                };
+                let blockchain: SharedBlockchain = Arc::new(Mutex::new(blockchain));
+                let transaction_pool: SharedTransactionPool = Arc::new(Mutex::new(transaction_pool));
+
+                let config = P2pConfig::from_env();
+                let network = Network::new(blockchain.clone(), transaction_pool.clone(), db, config.clone());
+                if config.is_enabled() {
+                    if let Err(err) = network.start().await {
+                        error!("Failed to start peer to peer networking: {}", err);
+                        return Err(rocket);
+                    }
+                }
+                let miner = Miner::new(blockchain.clone(), transaction_pool.clone(), network.clone(), MinerConfig::from_env());
+                let pool_config = PoolConfig::from_env();
+                let stratum = StratumServer::new(blockchain.clone(), miner.clone(), pool_config.clone());
+                if pool_config.is_enabled() {
+                    if let Err(err) = stratum.start().await {
+                        error!("Failed to start the mining pool: {}", err);
+                        return Err(rocket);
+                    }
+                }
+                Ok(rocket.manage(blockchain).manage(transaction_pool).manage(network).manage(miner).manage(stratum))
+            }
+        }))
+        // Lets mining stop instead of holding up shutdown.
+        .attach(rocket::fairing::AdHoc::on_shutdown("Cancel Mining", {
+            let shutdown = shutdown.clone();
+            |rocket| Box::pin(async move {
+                shutdown.cancel();
+                if let Some(miner) = rocket.state::<Miner>() {
+                    miner.stop();
+                }
+            })
         }))
         .manage(db)
-        .mount("/", routes![transaction, get_blockchain, mine, get_transactions, get_balance, index])
-}
\ No newline at end of file
+        .manage(shutdown)
+        .mount("/", routes![transaction, get_transaction, get_blockchain, mine, get_transactions, get_balance, get_merkle_proof, reindex, get_peers, start_miner, stop_miner, miner_status, get_block_template, submit_block, get_pool, index])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::db::memory::core::MemoryStore;
+    use rocket::local::asynchronous::Client;
+
+    #[rocket::async_test]
+    async fn looks_up_transactions_in_the_pool_and_the_store() {
+        let db: Store = Arc::new(MemoryStore::new());
+        let pool: SharedTransactionPool = Arc::new(Mutex::new(TransactionPool::new(db.clone())));
+        let rocket = rocket::build().manage(db.clone()).manage(pool.clone()).mount("/", routes![get_transaction]);
+        let client = Client::tracked(rocket).await.unwrap();
+
+        let pending = Transaction::new(Address::from([1; 20]), Address::from([2; 20]), Amount::from_coins(1), 1, Amount::ZERO);
+        let stored = Transaction::new(Address::from([1; 20]), Address::from([2; 20]), Amount::from_coins(2), 2, Amount::ZERO);
+        pool.lock().await.pool.push(pending.clone());
+        db.insert_transaction(&stored).await.unwrap();
+
+        for transaction in [pending, stored] {
+            let id = transaction.id();
+            let response = client.get(format!("/transaction/{}", id)).dispatch().await;
+            assert_eq!(response.status(), Status::Ok);
+            let body: serde_json::Value = response.into_json().await.unwrap();
+            assert_eq!(body["id"], id.as_hex());
+            assert_eq!(body["transaction"]["amount"], serde_json::to_value(transaction.amount).unwrap());
+        }
+
+        let unknown = client.get(format!("/transaction/{}", TransactionID::from([0; 32]))).dispatch().await;
+        assert_eq!(unknown.status(), Status::NotFound);
+        let malformed = client.get("/transaction/not-an-id").dispatch().await;
+        assert_eq!(malformed.status(), Status::NotFound);
+    }
+}
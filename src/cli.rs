@@ -0,0 +1,53 @@
+use std::error::Error;
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+
+use log::info;
+
+use crate::blockchain::core::Blockchain;
+use crate::blockchain::db::core::open_store;
+
+pub const USAGE: &str = "usage: Serenity [serve | reindex | export <file> | import <file>]";
+
+/// Runs a maintenance subcommand against the store configured through the
+/// environment, the same one `serve` would use.
+pub async fn run(command: &str, args: &[String]) -> Result<(), Box<dyn Error>> {
+    match (command, args) {
+        ("reindex", []) => reindex().await,
+        ("export", [path]) => export(path).await,
+        ("import", [path]) => import(path).await,
+        _ => Err(format!("unknown command or arguments {:?} {:?}\n{}", command, args, USAGE).into()),
+    }
+}
+
+async fn open_blockchain() -> Result<Blockchain, Box<dyn Error>> {
+    let db = open_store().await?;
+    db.migrate().await?;
+    Ok(Blockchain::new(db).await?)
+}
+
+async fn reindex() -> Result<(), Box<dyn Error>> {
+    let mut blockchain = open_blockchain().await?;
+    let report = blockchain.reindex().await?;
+    info!("Reindex finished: {:?}", report);
+    Ok(())
+}
+
+/// Writes the stored chain to `path` as newline-delimited JSON blocks.
+async fn export(path: &str) -> Result<(), Box<dyn Error>> {
+    let blockchain = open_blockchain().await?;
+    let exported = blockchain.export(BufWriter::new(File::create(path)?)).await?;
+    info!("Exported {} blocks to {}", exported, path);
+    Ok(())
+}
+
+/// Validates and loads the blocks in `path` into the store. The store must
+/// be empty or hold a prefix of the same chain.
+async fn import(path: &str) -> Result<(), Box<dyn Error>> {
+    let db = open_store().await?;
+    db.migrate().await?;
+    let (_, report) = Blockchain::import(db, BufReader::new(File::open(path)?)).await?;
+    info!("Import finished: {:?}", report);
+    Ok(())
+}
@@ -1,24 +1,50 @@
 #[macro_use]
 extern crate rocket;
 mod blockchain {
+    pub mod amount;
     pub mod block;
     pub mod core;
     pub mod hashing;
+    pub mod merkle;
+    pub mod miner;
+    pub mod p2p {
+        pub mod core;
+        pub mod message;
+        pub mod peers;
+        pub mod sync;
+    }
+    pub mod snapshot;
+    pub mod stratum {
+        pub mod core;
+        pub mod message;
+    }
+    #[cfg(test)]
+    pub mod test_util;
     pub mod transaction;
     pub mod transaction_pool;
+    pub mod tree;
     pub mod wallet;
     pub mod web {
         pub mod core;
     }
     pub mod db {
         pub mod core;
-        pub mod tables;
+        pub mod memory {
+            pub mod core;
+        }
         pub mod mongodb {
             pub mod core;
+            pub mod migrations;
+        }
+        pub mod sqlite {
+            pub mod core;
+            pub mod tables;
         }
     }
 }
 
+mod cli;
+
 mod utils {
     pub mod logging;
     pub mod calculations;
@@ -27,5 +53,16 @@ mod utils {
 #[allow(dead_code)]
 async fn main() {
     utils::logging::setup_logger();
-    let _ = blockchain::web::core::rocket().await.launch().await;
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        None | Some("serve") => {
+            let _ = blockchain::web::core::rocket().await.launch().await;
+        }
+        Some(command) => {
+            if let Err(err) = cli::run(command, &args[1..]).await {
+                log::error!("{}", err);
+                std::process::exit(1);
+            }
+        }
+    }
 }
\ No newline at end of file
@@ -1,51 +1,85 @@
-use crate::blockchain::{block::Block, transaction_pool::TransactionPool};
+use crate::blockchain::{amount::Amount, block::Block, hashing::BlockHeader};
 
 const TARGET_BLOCK_TIME: u64 = 60; // Target block time in seconds
-const DIFFICULTY_ADJUSTMENT_INTERVAL: usize = 10; // Number of blocks to consider for difficulty adjustment
+pub const DIFFICULTY_ADJUSTMENT_INTERVAL: usize = 10; // Number of blocks to consider for difficulty adjustment
 
-pub fn calculate_fee(amount: f64) -> f64 {
-    let fee_percentage = 0.01; // 1% transaction fee
-    amount * fee_percentage
+pub fn calculate_fee(amount: Amount) -> Amount {
+    let fee_percentage = 1; // 1% transaction fee
+    amount.percent(fee_percentage)
 }
 
-pub fn calculate_block_subsidy(height: u64) -> f64 {
+pub fn calculate_block_subsidy(height: u64) -> Amount {
     let halving_interval = 210_000;
-    let subsidy = 50.0;
-    let mut total_subsidy = subsidy;
+    let subsidy = Amount::from_coins(50);
+    let halvings = height / halving_interval;
 
-    for _ in 0..(height / halving_interval) {
-        total_subsidy /= 2.0;
+    Amount::from_base_units(subsidy.base_units().checked_shr(halvings as u32).unwrap_or(0))
+}
+
+const REWARD_SCALING_PERCENT: u64 = 1;
+
+pub fn calculate_mining_reward(height: u64, total_fee: Amount) -> Amount {
+    let subsidy = calculate_block_subsidy(height);
+
+    subsidy.checked_add(total_fee).unwrap_or(Amount::MAX).percent(REWARD_SCALING_PERCENT)
+}
+
+/// The parts of a block or header that difficulty adjustment looks at.
+pub trait DifficultySample {
+    fn timestamp(&self) -> u64;
+    fn difficulty(&self) -> u32;
+}
+
+impl DifficultySample for Block {
+    fn timestamp(&self) -> u64 {
+        self.timestamp
     }
 
-    total_subsidy
+    fn difficulty(&self) -> u32 {
+        self.difficulty
+    }
 }
 
-const REWARD_SCALING_FACTOR: f64 = 0.01;
+impl DifficultySample for BlockHeader {
+    fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
 
-pub fn calculate_mining_reward(height: u64, pool: &TransactionPool) -> f64 {
-    let subsidy = calculate_block_subsidy(height);
-    let total_fee = pool.pool.iter().map(|tx| tx.fee).sum::<f64>();
+    fn difficulty(&self) -> u32 {
+        self.difficulty
+    }
+}
 
-    (subsidy + total_fee) * REWARD_SCALING_FACTOR
+impl<T: DifficultySample> DifficultySample for &T {
+    fn timestamp(&self) -> u64 {
+        (*self).timestamp()
+    }
+
+    fn difficulty(&self) -> u32 {
+        (*self).difficulty()
+    }
 }
 
-pub fn calculate_difficulty(chain: &Vec<Block>) -> u32 {
+/// The difficulty the block after `chain` must have. Only the last
+/// `DIFFICULTY_ADJUSTMENT_INTERVAL` blocks matter, so `chain` can be just
+/// those as long as it reaches back to genesis when the chain is shorter.
+pub fn calculate_difficulty<T: DifficultySample>(chain: &[T]) -> u32 {
     if chain.len() < DIFFICULTY_ADJUSTMENT_INTERVAL {
         return 1;
     }
 
     let last_block = chain.last().unwrap();
     let prev_adjustment_block = chain.get(chain.len() - DIFFICULTY_ADJUSTMENT_INTERVAL).unwrap();
-    let time_diff = last_block.timestamp - prev_adjustment_block.timestamp;
+    let time_diff = last_block.timestamp().saturating_sub(prev_adjustment_block.timestamp());
     let expected_time = TARGET_BLOCK_TIME * DIFFICULTY_ADJUSTMENT_INTERVAL as u64;
 
-    let mut difficulty = last_block.difficulty;
+    let mut difficulty = last_block.difficulty();
 
     if time_diff < expected_time {
-        difficulty += 1;
+        difficulty = difficulty.saturating_add(1);
     } else if difficulty > 1 {
         difficulty -= 1;
     }
 
     difficulty
-}
\ No newline at end of file
+}
@@ -1,7 +1,6 @@
 #[cfg(test)]
 mod tests {
     use reqwest::Client;
-    use tokio;
     use std::env;
 
     #[tokio::test]
@@ -13,7 +12,7 @@ mod tests {
         let response = client.post(url)
             .body(
                 r#"{
-                    "address": "blah"
+                    "address": "SaVQRri1UUeBkKQJPRkAM5MLsgSDDibWbd"
                 }"#
             )
             .send()